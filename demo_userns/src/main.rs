@@ -1,38 +1,149 @@
+mod probe;
+mod report;
+
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
 use clap::Parser;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sched::{clone, CloneFlags};
-use nix::unistd::{geteuid, getegid};
 use nix::sys::wait::waitpid;
-use caps::CapSet;
+use nix::unistd::{getegid, geteuid, Pid};
+
+use crate::report::Report;
 
 #[macro_use]
 extern crate log;
 
 const STACK_SIZE: usize = 1024 * 1024;
 
-pub fn child_func(args: Option<Vec<String>>) -> isize {
+/// Print credential / capability report to stdout
+fn print_report(report: &Report, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        println!("{}", serde_json::to_string(report)?);
+    } else {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
+/// Print changes between two reports, prefixed with the elapsed time
+fn print_changes(old: &Report, new: &Report, elapsed: Duration, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        let line = serde_json::json!({
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "changes": old.diff(new),
+            "report": new,
+        });
+        println!("{}", line);
+    } else {
+        for change in old.diff(new) {
+            println!("[+{:.3}s] {}", elapsed.as_secs_f64(), change);
+        }
+    }
+    Ok(())
+}
+
+/// Observe credentials until the parent closes `done_fd`
+///
+/// The report is collected every `interval` and printed whenever it
+/// changes, so the effect of the parent writing ID maps becomes visible.
+fn observe(done_fd: RawFd, interval: Duration, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut last = Report::collect()?;
+    print_report(&last, json)?;
+    let mut fds = [PollFd::new(done_fd, PollFlags::POLLIN)];
     loop {
-        info!("eUID = {}; eGID = {}", geteuid(), getegid());
-        let cur_caps = caps::read(None, CapSet::Permitted);
-        info!("capabilities: {:?}", cur_caps);
-        if args.is_none() {
-            break;
+        let finished = match poll(&mut fds, interval.as_millis() as i32) {
+            Ok(n) => n > 0,
+            Err(nix::errno::Errno::EINTR) => false,
+            Err(err) => return Err(err.into()),
+        };
+        let current = Report::collect()?;
+        if current != last {
+            print_changes(&last, &current, started.elapsed(), json)?;
+            last = current;
+        }
+        if finished {
+            return Ok(());
+        }
+    }
+}
+
+/// Wait until the parent has written ID maps, then try privileged operations
+fn run_probes(done_fd: RawFd, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 1];
+    while nix::unistd::read(done_fd, &mut buf)? > 0 {}
+    let results = probe::run_all(&std::env::temp_dir())?;
+    if json {
+        println!("{}", serde_json::to_string(&results)?);
+    } else {
+        for result in results {
+            println!("{}", result);
         }
-        std::thread::sleep(std::time::Duration::from_secs(5));
     }
-    return 0
+    Ok(())
+}
+
+pub fn child_func(args: &Args, done_fd: RawFd) -> isize {
+    let result = if args.probe {
+        run_probes(done_fd, args.json)
+    } else if args.observe {
+        observe(done_fd, Duration::from_millis(args.interval), args.json)
+    } else {
+        Report::collect().and_then(|report| print_report(&report, args.json))
+    };
+    if let Err(err) = result {
+        error!("child: {}", err);
+        return 1
+    }
+    0
+}
+
+/// Map root in the child's user namespace to our eUID / eGID
+///
+/// setgroups has to be denied before an unprivileged process may write gid_map.
+fn write_id_maps(pid: Pid) -> std::io::Result<()> {
+    let proc_dir = std::path::Path::new("/proc").join(pid.to_string());
+    std::fs::write(proc_dir.join("setgroups"), "deny")?;
+    std::fs::write(proc_dir.join("gid_map"), format!("0 {} 1\n", getegid()))?;
+    std::fs::write(proc_dir.join("uid_map"), format!("0 {} 1\n", geteuid()))
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    args: Option<Vec<String>>
+pub struct Args {
+    /// Print Report as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    /// Keep Reporting Changes While Parent Writes ID Maps
+    #[arg(short, long, default_value_t = false)]
+    observe: bool,
+    /// Try Privileged Operations Inside the New User Namespace
+    #[arg(short, long, default_value_t = false, conflicts_with = "observe")]
+    probe: bool,
+    /// Observe Interval in Milliseconds
+    #[arg(short, long, default_value_t = 100)]
+    interval: u64,
+    /// Delay Before Parent Writes ID Maps in Milliseconds (for --observe)
+    #[arg(short = 'd', long, default_value_t = 1000)]
+    map_delay: u64,
 }
 
 fn main() -> std::process::ExitCode {
     env_logger::init();
     let args = Args::parse();
+    // parent closes write end once it's done with the ID maps
+    let (done_r, done_w) = match nix::unistd::pipe() {
+        Ok(fds) => fds,
+        Err(err) => {
+            error!("pipe: {}", err);
+            return std::process::ExitCode::from(1)
+        },
+    };
     let cb = Box::new(|| {
-        child_func(args.args.clone())
+        let _ = nix::unistd::close(done_w);
+        child_func(&args, done_r)
     });
     let mut child_stack = vec![0; STACK_SIZE];
     let mut flags = CloneFlags::empty();
@@ -44,9 +155,26 @@ fn main() -> std::process::ExitCode {
             return std::process::ExitCode::from(1)
         },
     };
-    if let Err(err) = waitpid(pid, None) {
-        error!("waitpid ({}): {}", pid, err);
-        return std::process::ExitCode::from(2)
+    let _ = nix::unistd::close(done_r);
+    if args.observe || args.probe {
+        if args.observe {
+            std::thread::sleep(Duration::from_millis(args.map_delay));
+        }
+        info!("writing ID maps of PID {}", pid);
+        if let Err(err) = write_id_maps(pid) {
+            error!("write ID maps ({}): {}", pid, err);
+        }
+    }
+    let _ = nix::unistd::close(done_w);
+    match waitpid(pid, None) {
+        Ok(nix::sys::wait::WaitStatus::Exited(_, 0)) => std::process::ExitCode::SUCCESS,
+        Ok(status) => {
+            error!("child ({}) failed: {:?}", pid, status);
+            std::process::ExitCode::from(3)
+        },
+        Err(err) => {
+            error!("waitpid ({}): {}", pid, err);
+            std::process::ExitCode::from(2)
+        },
     }
-    return std::process::ExitCode::SUCCESS;
 }
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, ForkResult, Gid, Uid};
+use serde::Serialize;
+
+/// Outcome of a single privileged operation
+#[derive(Serialize, Debug, Clone)]
+pub struct ProbeResult {
+    pub name: String,
+    pub allowed: bool,
+    /// errno name (e.g. `EPERM`) when denied
+    pub errno: Option<String>,
+    /// human readable description of the errno when denied
+    pub error: Option<String>,
+}
+
+/// Privileged operation tried inside the new user namespace
+///
+/// It receives a scratch directory which belongs to the probe alone.
+type ProbeFn = fn(&Path) -> nix::Result<()>;
+
+/// All probes, in the order they're reported
+const PROBES: &[(&str, ProbeFn)] = &[
+    ("sethostname (new UTS namespace)", probe_sethostname),
+    ("mount tmpfs (new mount namespace)", probe_mount_tmpfs),
+    ("mount proc (new mount namespace)", probe_mount_proc),
+    ("mount sysfs (new mount namespace)", probe_mount_sysfs),
+    ("mount overlay (new mount namespace)", probe_mount_overlay),
+    ("create network namespace", probe_netns),
+    ("bring lo up (new network namespace)", probe_netns_lo),
+    ("chown 1:1 (unmapped IDs)", probe_chown),
+    ("mknod c 1:3", probe_mknod),
+    ("setgroups [0]", probe_setgroups),
+    ("sysctl kernel.hostname (new UTS namespace)", probe_sysctl_hostname),
+    ("sysctl net.ipv4.ip_forward (new network namespace)", probe_sysctl_ip_forward),
+    ("sysctl vm.swappiness", probe_sysctl_swappiness),
+];
+
+fn io_errno(err: std::io::Error) -> Errno {
+    Errno::from_i32(err.raw_os_error().unwrap_or(nix::libc::EIO))
+}
+
+/// Write the current value of a sysctl back to it
+///
+/// This checks write permission without changing anything.
+fn rewrite_sysctl(path: &str) -> nix::Result<()> {
+    let value = std::fs::read(path).map_err(io_errno)?;
+    std::fs::write(path, value).map_err(io_errno)
+}
+
+fn probe_sethostname(_dir: &Path) -> nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWUTS)?;
+    nix::unistd::sethostname("probe")
+}
+
+fn probe_mount_fs(dir: &Path, fstype: &str) -> nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    mount(Some(fstype), dir, Some(fstype), MsFlags::empty(), None::<&str>)
+}
+
+fn probe_mount_tmpfs(dir: &Path) -> nix::Result<()> {
+    probe_mount_fs(dir, "tmpfs")
+}
+
+fn probe_mount_proc(dir: &Path) -> nix::Result<()> {
+    probe_mount_fs(dir, "proc")
+}
+
+fn probe_mount_sysfs(dir: &Path) -> nix::Result<()> {
+    probe_mount_fs(dir, "sysfs")
+}
+
+fn probe_mount_overlay(dir: &Path) -> nix::Result<()> {
+    let dirs: Vec<PathBuf> = ["lower", "upper", "work", "merged"].iter().map(|d| dir.join(d)).collect();
+    for d in &dirs {
+        std::fs::create_dir(d).map_err(io_errno)?;
+    }
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        dirs[0].display(),
+        dirs[1].display(),
+        dirs[2].display()
+    );
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    mount(Some("overlay"), &dirs[3], Some("overlay"), MsFlags::empty(), Some(options.as_str()))
+}
+
+fn probe_netns(_dir: &Path) -> nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWNET)
+}
+
+fn probe_netns_lo(_dir: &Path) -> nix::Result<()> {
+    use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+    unshare(CloneFlags::CLONE_NEWNET)?;
+    let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    let mut ifr: nix::libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(b"lo\0") {
+        *dst = *src as nix::libc::c_char;
+    }
+    Errno::result(unsafe { nix::libc::ioctl(sock, nix::libc::SIOCGIFFLAGS as _, &mut ifr) })?;
+    unsafe { ifr.ifr_ifru.ifru_flags |= nix::libc::IFF_UP as nix::libc::c_short };
+    Errno::result(unsafe { nix::libc::ioctl(sock, nix::libc::SIOCSIFFLAGS as _, &ifr) })?;
+    nix::unistd::close(sock)
+}
+
+/// Hand a file to IDs other than the mapped root, which already owns it
+///
+/// Only 0 is mapped, so this fails with EINVAL: IDs without a mapping
+/// can't be used even by root in the namespace.
+fn probe_chown(dir: &Path) -> nix::Result<()> {
+    let file = dir.join("file");
+    std::fs::write(&file, b"").map_err(io_errno)?;
+    nix::unistd::chown(&file, Some(Uid::from_raw(1)), Some(Gid::from_raw(1)))
+}
+
+fn probe_mknod(dir: &Path) -> nix::Result<()> {
+    mknod(&dir.join("null"), SFlag::S_IFCHR, Mode::from_bits_truncate(0o666), makedev(1, 3))
+}
+
+fn probe_setgroups(_dir: &Path) -> nix::Result<()> {
+    nix::unistd::setgroups(&[Gid::from_raw(0)])
+}
+
+fn probe_sysctl_hostname(_dir: &Path) -> nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWUTS)?;
+    rewrite_sysctl("/proc/sys/kernel/hostname")
+}
+
+fn probe_sysctl_ip_forward(_dir: &Path) -> nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWNET)?;
+    rewrite_sysctl("/proc/sys/net/ipv4/ip_forward")
+}
+
+fn probe_sysctl_swappiness(_dir: &Path) -> nix::Result<()> {
+    rewrite_sysctl("/proc/sys/vm/swappiness")
+}
+
+/// Run a probe in a forked process, so namespaces it unshares don't leak
+///
+/// The forked process reports the errno through its exit status.
+fn run_probe(name: &str, probe: ProbeFn, dir: &Path) -> ProbeResult {
+    let result = match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let code = match probe(dir) {
+                Ok(()) => 0,
+                Err(errno) => errno as i32,
+            };
+            unsafe { nix::libc::_exit(code) }
+        },
+        Ok(ForkResult::Parent { child }) => match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+            Ok(WaitStatus::Exited(_, code)) => Err(Errno::from_i32(code)),
+            Ok(status) => {
+                warn!("probe {:?} terminated abnormally: {:?}", name, status);
+                Err(Errno::UnknownErrno)
+            },
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    ProbeResult {
+        name: name.to_string(),
+        allowed: result.is_ok(),
+        errno: result.err().map(|e| format!("{:?}", e)),
+        error: result.err().map(|e| e.desc().to_string()),
+    }
+}
+
+/// Try every probe inside a scratch directory under `base`
+pub fn run_all(base: &Path) -> std::io::Result<Vec<ProbeResult>> {
+    let root = base.join(format!("demo_userns-probe-{}", nix::unistd::getpid()));
+    std::fs::create_dir(&root)?;
+    let mut results = vec![];
+    for (i, (name, probe)) in PROBES.iter().enumerate() {
+        let dir = root.join(i.to_string());
+        std::fs::create_dir(&dir)?;
+        results.push(run_probe(name, *probe, &dir));
+    }
+    if let Err(err) = std::fs::remove_dir_all(&root) {
+        warn!("remove {}: {}", root.display(), err);
+    }
+    Ok(results)
+}
+
+impl fmt::Display for ProbeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.allowed {
+            write!(f, "{:<52} allowed", self.name)
+        } else {
+            write!(
+                f,
+                "{:<52} denied ({}: {})",
+                self.name,
+                self.errno.as_deref().unwrap_or_default(),
+                self.error.as_deref().unwrap_or_default()
+            )
+        }
+    }
+}
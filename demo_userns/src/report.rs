@@ -0,0 +1,241 @@
+use std::error::Error;
+use std::fmt;
+use std::os::unix::io::AsRawFd;
+
+use caps::CapSet;
+use serde::Serialize;
+
+/// ioctl(2) request returning the owner UID of a user namespace
+/// (`_IO(0xb7, 0x4)` from linux/nsfs.h)
+const NS_GET_OWNER_UID: u64 = 0xb704;
+
+/// Single line of `/proc/PID/uid_map` or `/proc/PID/gid_map`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdMapEntry {
+    pub inside: u32,
+    pub outside: u32,
+    pub length: u32,
+}
+
+/// Real, effective, saved and filesystem IDs
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ids {
+    pub real: u32,
+    pub effective: u32,
+    pub saved: u32,
+    pub fs: u32,
+}
+
+/// All five capability sets of the calling thread
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub effective: Vec<String>,
+    pub permitted: Vec<String>,
+    pub inheritable: Vec<String>,
+    pub bounding: Vec<String>,
+    pub ambient: Vec<String>,
+}
+
+/// Credential and capability report of the calling process
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub pid: i32,
+    pub uid: Ids,
+    pub gid: Ids,
+    pub groups: Vec<u32>,
+    pub uid_map: Vec<IdMapEntry>,
+    pub gid_map: Vec<IdMapEntry>,
+    pub setgroups: String,
+    pub securebits: u32,
+    pub no_new_privs: bool,
+    pub owner_uid: u32,
+    pub capabilities: Capabilities,
+}
+
+/// Read a capability set as sorted capability names
+fn read_caps(set: CapSet) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut names: Vec<String> = caps::read(None, set)?.iter().map(|c| c.to_string()).collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Parse content of `uid_map` / `gid_map`
+fn parse_id_map(content: &str) -> Result<Vec<IdMapEntry>, Box<dyn Error>> {
+    let mut entries = vec![];
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() != 3 {
+            return Err(format!("malformed id map line: {:?}", line).into());
+        }
+        entries.push(IdMapEntry {
+            inside: fields[0].parse()?,
+            outside: fields[1].parse()?,
+            length: fields[2].parse()?,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_id_map(path: &str) -> Result<Vec<IdMapEntry>, Box<dyn Error>> {
+    parse_id_map(&std::fs::read_to_string(path)?)
+}
+
+/// prctl(2) option which only returns a value
+fn prctl_get(option: nix::libc::c_int) -> nix::Result<nix::libc::c_int> {
+    let res = unsafe { nix::libc::prctl(option, 0, 0, 0, 0) };
+    nix::errno::Errno::result(res)
+}
+
+/// Owner UID of the user namespace of the calling process
+fn owner_uid() -> Result<u32, Box<dyn Error>> {
+    let ns = std::fs::File::open("/proc/self/ns/user")?;
+    let mut uid: nix::libc::uid_t = 0;
+    let res = unsafe { nix::libc::ioctl(ns.as_raw_fd(), NS_GET_OWNER_UID as _, &mut uid) };
+    nix::errno::Errno::result(res)?;
+    Ok(uid)
+}
+
+impl Report {
+    /// Collect report of the calling process
+    pub fn collect() -> Result<Self, Box<dyn Error>> {
+        let resuid = nix::unistd::getresuid()?;
+        let resgid = nix::unistd::getresgid()?;
+        // passing an invalid ID leaves fsuid / fsgid untouched and returns current one
+        let fsuid = nix::unistd::setfsuid(nix::unistd::Uid::from_raw(u32::MAX));
+        let fsgid = nix::unistd::setfsgid(nix::unistd::Gid::from_raw(u32::MAX));
+        Ok(Self {
+            pid: nix::unistd::getpid().as_raw(),
+            uid: Ids {
+                real: resuid.real.as_raw(),
+                effective: resuid.effective.as_raw(),
+                saved: resuid.saved.as_raw(),
+                fs: fsuid.as_raw(),
+            },
+            gid: Ids {
+                real: resgid.real.as_raw(),
+                effective: resgid.effective.as_raw(),
+                saved: resgid.saved.as_raw(),
+                fs: fsgid.as_raw(),
+            },
+            groups: nix::unistd::getgroups()?.iter().map(|g| g.as_raw()).collect(),
+            uid_map: read_id_map("/proc/self/uid_map")?,
+            gid_map: read_id_map("/proc/self/gid_map")?,
+            setgroups: std::fs::read_to_string("/proc/self/setgroups")?.trim().to_string(),
+            securebits: prctl_get(nix::libc::PR_GET_SECUREBITS)? as u32,
+            no_new_privs: prctl_get(nix::libc::PR_GET_NO_NEW_PRIVS)? != 0,
+            owner_uid: owner_uid()?,
+            capabilities: Capabilities {
+                effective: read_caps(CapSet::Effective)?,
+                permitted: read_caps(CapSet::Permitted)?,
+                inheritable: read_caps(CapSet::Inheritable)?,
+                bounding: read_caps(CapSet::Bounding)?,
+                ambient: read_caps(CapSet::Ambient)?,
+            },
+        })
+    }
+}
+
+/// Capabilities gained (`+CAP_X`) and lost (`-CAP_X`) between two sets
+fn diff_caps(old: &[String], new: &[String]) -> String {
+    let mut changes = vec![];
+    for cap in new.iter().filter(|c| !old.contains(c)) {
+        changes.push(format!("+{}", cap));
+    }
+    for cap in old.iter().filter(|c| !new.contains(c)) {
+        changes.push(format!("-{}", cap));
+    }
+    changes.join(" ")
+}
+
+fn fmt_ids(ids: &Ids) -> String {
+    format!("real={} effective={} saved={} fs={}", ids.real, ids.effective, ids.saved, ids.fs)
+}
+
+fn fmt_id_map(entries: &[IdMapEntry]) -> String {
+    if entries.is_empty() {
+        return "(unmapped)".to_string();
+    }
+    entries
+        .iter()
+        .map(|e| format!("{} {} {}", e.inside, e.outside, e.length))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn fmt_caps(caps: &[String]) -> String {
+    if caps.is_empty() {
+        return "(none)".to_string();
+    }
+    caps.join(" ")
+}
+
+impl Report {
+    /// Describe every field which differs between `self` and the newer report
+    pub fn diff(&self, new: &Report) -> Vec<String> {
+        let mut changes = vec![];
+        if self.uid != new.uid {
+            changes.push(format!("UID: {} -> {}", fmt_ids(&self.uid), fmt_ids(&new.uid)));
+        }
+        if self.gid != new.gid {
+            changes.push(format!("GID: {} -> {}", fmt_ids(&self.gid), fmt_ids(&new.gid)));
+        }
+        if self.groups != new.groups {
+            changes.push(format!("groups: {:?} -> {:?}", self.groups, new.groups));
+        }
+        if self.uid_map != new.uid_map {
+            changes.push(format!("uid_map: {} -> {}", fmt_id_map(&self.uid_map), fmt_id_map(&new.uid_map)));
+        }
+        if self.gid_map != new.gid_map {
+            changes.push(format!("gid_map: {} -> {}", fmt_id_map(&self.gid_map), fmt_id_map(&new.gid_map)));
+        }
+        if self.setgroups != new.setgroups {
+            changes.push(format!("setgroups: {} -> {}", self.setgroups, new.setgroups));
+        }
+        if self.securebits != new.securebits {
+            changes.push(format!("securebits: {:#x} -> {:#x}", self.securebits, new.securebits));
+        }
+        if self.no_new_privs != new.no_new_privs {
+            changes.push(format!("no_new_privs: {} -> {}", self.no_new_privs, new.no_new_privs));
+        }
+        if self.owner_uid != new.owner_uid {
+            changes.push(format!("owner UID: {} -> {}", self.owner_uid, new.owner_uid));
+        }
+        let sets = [
+            ("effective", &self.capabilities.effective, &new.capabilities.effective),
+            ("permitted", &self.capabilities.permitted, &new.capabilities.permitted),
+            ("inheritable", &self.capabilities.inheritable, &new.capabilities.inheritable),
+            ("bounding", &self.capabilities.bounding, &new.capabilities.bounding),
+            ("ambient", &self.capabilities.ambient, &new.capabilities.ambient),
+        ];
+        for (name, old_set, new_set) in sets {
+            if old_set != new_set {
+                changes.push(format!("{} capabilities: {}", name, diff_caps(old_set, new_set)));
+            }
+        }
+        changes
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "PID          = {}", self.pid)?;
+        writeln!(f, "UID          = {}", fmt_ids(&self.uid))?;
+        writeln!(f, "GID          = {}", fmt_ids(&self.gid))?;
+        writeln!(f, "groups       = {:?}", self.groups)?;
+        writeln!(f, "uid_map      = {}", fmt_id_map(&self.uid_map))?;
+        writeln!(f, "gid_map      = {}", fmt_id_map(&self.gid_map))?;
+        writeln!(f, "setgroups    = {}", self.setgroups)?;
+        writeln!(f, "securebits   = {:#x}", self.securebits)?;
+        writeln!(f, "no_new_privs = {}", self.no_new_privs)?;
+        writeln!(f, "owner UID    = {}", self.owner_uid)?;
+        writeln!(f, "capabilities:")?;
+        writeln!(f, "  effective   = {}", fmt_caps(&self.capabilities.effective))?;
+        writeln!(f, "  permitted   = {}", fmt_caps(&self.capabilities.permitted))?;
+        writeln!(f, "  inheritable = {}", fmt_caps(&self.capabilities.inheritable))?;
+        writeln!(f, "  bounding    = {}", fmt_caps(&self.capabilities.bounding))?;
+        write!(f, "  ambient     = {}", fmt_caps(&self.capabilities.ambient))
+    }
+}
@@ -1,51 +1,157 @@
-use core::time;
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
 
+use clap::Parser;
 use nix::sched::clone;
+use nix::sys::signal::{SigSet, Signal};
 use nix::sys::wait::waitpid;
 
-fn child_func() -> isize {
-    const HOSTNAME_VALUE: &str = "hellowed";
-    println!("set hostname as hellowed");
-    if let Err(result) = nix::unistd::sethostname(HOSTNAME_VALUE) {
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Hostname to Set in the New UTS Namespace
+    #[arg(long, default_value = "hellowed")]
+    hostname: String,
+    /// NIS Domain Name to Set in the New UTS Namespace
+    #[arg(long)]
+    domainname: Option<String>,
+    /// Keep the Namespace Alive Until SIGINT or SIGTERM Arrives
+    #[arg(long, default_value_t = false)]
+    hold: bool,
+}
+
+/// Signals which release a child started with `--hold`
+fn hold_signals() -> SigSet {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGINT);
+    mask.add(Signal::SIGTERM);
+    mask
+}
+
+/// Pass SIGINT / SIGTERM the parent gets on to the held child, until it exits
+///
+/// SIGCHLD is blocked along with them, so its arrival ends the wait.
+fn forward_signals(child: nix::unistd::Pid) -> nix::Result<()> {
+    let mut mask = hold_signals();
+    mask.add(Signal::SIGCHLD);
+    loop {
+        match mask.wait()? {
+            Signal::SIGCHLD => return Ok(()),
+            sig => {
+                println!("forward {} to child", sig);
+                match nix::sys::signal::kill(child, sig) {
+                    Ok(()) | Err(nix::errno::Errno::ESRCH) => {},
+                    Err(err) => return Err(err),
+                }
+            },
+        }
+    }
+}
+
+fn setdomainname(name: &str) -> nix::Result<()> {
+    let name = CString::new(name).map_err(|_| nix::errno::Errno::EINVAL)?;
+    let res = unsafe { nix::libc::setdomainname(name.as_ptr(), name.as_bytes().len()) };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+fn child_func(args: &Args, ready_fd: RawFd) -> isize {
+    println!("set hostname as {}", args.hostname);
+    if let Err(result) = nix::unistd::sethostname(&args.hostname) {
         println!("got error while set hostname: {}", result);
         return 1
     }
-    println!("get hostname");
-    let hostname_result = nix::unistd::gethostname();
-    match hostname_result {
-        Ok(hostname) => {
-            println!("hostname is: {}", hostname.to_str().unwrap());
-            std::thread::sleep(time::Duration::from_secs(100));
-            return 0
-        },
-        Err(_) => {
-            println!("got error");
+    if let Some(domainname) = &args.domainname {
+        println!("set domainname as {}", domainname);
+        if let Err(result) = setdomainname(domainname) {
+            println!("got error while set domainname: {}", result);
             return 1
-        },
-    };
+        }
+    }
+    println!("get hostname");
+    let uts = nix::sys::utsname::uname().unwrap();
+    println!("hostname is: {}", uts.nodename().to_str().unwrap());
+    println!("domainname is: {}", uts.domainname().to_str().unwrap());
+    // tell parent that hostname has been changed
+    if let Err(err) = nix::unistd::write(ready_fd, &[1]) {
+        println!("got error while notify parent: {}", err);
+        return 1
+    }
+    let _ = nix::unistd::close(ready_fd);
+    if args.hold {
+        println!("holding namespace until SIGINT or SIGTERM");
+        match hold_signals().wait() {
+            Ok(sig) => println!("got {}, releasing namespace", sig),
+            Err(err) => {
+                println!("got error while wait signal: {}", err);
+                return 1
+            }
+        }
+    }
+    0
 }
 
 fn main() -> std::process::ExitCode {
     const STACK_SIZE: usize = 1024 * 1024;
+    let args = Args::parse();
+    if args.hold {
+        // Blocked before clone, so the child inherits the mask and
+        // receives them with sigwait(); the parent forwards those sent to it.
+        // SIGCHLD is discarded unless blocked, so it's blocked here too
+        let mut mask = hold_signals();
+        mask.add(Signal::SIGCHLD);
+        if let Err(err) = mask.thread_block() {
+            println!("got error while block signals: {}", err);
+            return std::process::ExitCode::from(4)
+        }
+    }
+    let (ready_r, ready_w) = match nix::unistd::pipe() {
+        Ok(fds) => fds,
+        Err(err) => {
+            println!("got error while pipe: {}", err);
+            return std::process::ExitCode::from(4)
+        }
+    };
     let mut stack = vec![0; STACK_SIZE];
     let flags = nix::sched::CloneFlags::CLONE_NEWUTS;
-    let child_pid_result = clone(Box::new(child_func), stack.as_mut_slice(), flags, Some(nix::sys::signal::SIGCHLD as i32));
+    let cb = Box::new(|| {
+        let _ = nix::unistd::close(ready_r);
+        child_func(&args, ready_w)
+    });
+    let child_pid_result = clone(cb, stack.as_mut_slice(), flags, Some(nix::sys::signal::SIGCHLD as i32));
+    let _ = nix::unistd::close(ready_w);
     match child_pid_result {
         Ok(pid_info) => {
-            println!("process has been created as pid {}", pid_info.to_string());
-            // sleep for 1 seconds
-            std::thread::sleep(time::Duration::from_secs(1));
-            match nix::unistd::gethostname() {
-                Ok(hostname) => {
-                    println!("parent hostname is: {}", hostname.to_str().unwrap())
+            println!("process has been created as pid {}", pid_info);
+            // wait for child to set hostname (EOF means child failed before)
+            let mut buf = [0u8; 1];
+            match nix::unistd::read(ready_r, &mut buf) {
+                Ok(1) => match nix::unistd::gethostname() {
+                    Ok(hostname) => {
+                        println!("parent hostname is: {}", hostname.to_str().unwrap())
+                    },
+                    Err(err) => {
+                        println!("got error while get parent hostname: {}", err)
+                    }
                 },
-                Err(err) => {
-                    println!("got error while get parent hostname: {}", err)
+                Ok(_) => println!("child exited before setting hostname"),
+                Err(err) => println!("got error while wait child: {}", err),
+            }
+            let _ = nix::unistd::close(ready_r);
+            if args.hold {
+                if let Err(err) = forward_signals(pid_info) {
+                    println!("got error while forward signals: {}", err);
                 }
             }
-            if let Err(waitpid_err) = waitpid(pid_info, None) {
-                println!("got error while wait: {}", waitpid_err);
-                return std::process::ExitCode::from(3)
+            match waitpid(pid_info, None) {
+                Ok(nix::sys::wait::WaitStatus::Exited(_, 0)) => {},
+                Ok(status) => {
+                    println!("child has failed: {:?}", status);
+                    return std::process::ExitCode::from(1)
+                },
+                Err(waitpid_err) => {
+                    println!("got error while wait: {}", waitpid_err);
+                    return std::process::ExitCode::from(3)
+                }
             }
             println!("child has been terminated");
         },
@@ -1,40 +1,191 @@
 use core::time;
+use std::os::unix::io::RawFd;
+
+use clap::{Parser, ValueEnum};
+use nix::sys::signal::{SigSet, Signal};
+use nix::time::{clock_gettime, ClockId};
 
 #[macro_use]
 extern crate log;
 
+/// Mechanism used by the child for noticing its parent's death
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Mode {
+    /// Ask the kernel for a signal with prctl(PR_SET_PDEATHSIG)
+    Pdeathsig,
+    /// Poll getppid() until it changes
+    Poll,
+    /// Poll a pidfd of the parent until it becomes readable
+    Pidfd,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Parent Death Notification Mechanism
+    #[arg(short, long, value_enum, default_value_t = Mode::Pdeathsig)]
+    mode: Mode,
+    /// Polling Interval in Microseconds (for --mode poll)
+    #[arg(short, long, default_value_t = 100000)]
+    interval: u64,
+}
+
+/// Signal delivered to the child when `--mode pdeathsig` is used
+const PDEATH_SIGNAL: Signal = Signal::SIGUSR1;
+
+/// Current CLOCK_MONOTONIC value in nanoseconds
+///
+/// CLOCK_MONOTONIC is system-wide, so values taken in the parent
+/// and the child can be compared with each other.
+fn monotonic_ns() -> nix::Result<u64> {
+    let ts = clock_gettime(ClockId::CLOCK_MONOTONIC)?;
+    Ok(ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64)
+}
+
+/// Wait until getppid() differs from the original parent
+fn wait_poll(ppid_orig: nix::unistd::Pid, interval: time::Duration) -> nix::Result<()> {
+    while nix::unistd::getppid() == ppid_orig {
+        std::thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// Arm PR_SET_PDEATHSIG, so the kernel signals us when the parent dies
+///
+/// The signal is blocked beforehand and collected with sigwait() later.
+fn arm_pdeathsig() -> nix::Result<SigSet> {
+    let mut mask = SigSet::empty();
+    mask.add(PDEATH_SIGNAL);
+    mask.thread_block()?;
+    let res = unsafe { nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, PDEATH_SIGNAL as nix::libc::c_ulong) };
+    nix::errno::Errno::result(res)?;
+    Ok(mask)
+}
+
+/// Wait for the signal armed with arm_pdeathsig()
+fn wait_pdeathsig(mask: &SigSet, ppid_orig: nix::unistd::Pid) -> nix::Result<()> {
+    // The parent may already be gone before PR_SET_PDEATHSIG took effect
+    if nix::unistd::getppid() != ppid_orig {
+        return Ok(());
+    }
+    let sig = mask.wait()?;
+    debug!("received {}", sig);
+    Ok(())
+}
+
+/// Open a pidfd referring to the parent process
+fn arm_pidfd(ppid_orig: nix::unistd::Pid) -> nix::Result<RawFd> {
+    let res = unsafe { nix::libc::syscall(nix::libc::SYS_pidfd_open, ppid_orig.as_raw(), 0) };
+    nix::errno::Errno::result(res).map(|fd| fd as RawFd)
+}
+
+/// Wait until the pidfd becomes readable (i.e. the parent exited)
+fn wait_pidfd(pidfd: RawFd) -> nix::Result<()> {
+    let mut fds = [nix::poll::PollFd::new(pidfd, nix::poll::PollFlags::POLLIN)];
+    loop {
+        match nix::poll::poll(&mut fds, -1) {
+            Ok(_) => break,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    nix::unistd::close(pidfd)
+}
+
 fn main() -> std::process::ExitCode {
     std::env::set_var("RUST_LOG", "DEBUG");
     env_logger::init();
+    let args = Args::parse();
     let ppid_orig = nix::unistd::getpid();
+    // ready_*: child tells parent that notification has been armed
+    // exit_*: parent passes its exit timestamp to child
+    let (ready_r, ready_w) = match nix::unistd::pipe() {
+        Ok(fds) => fds,
+        Err(err) => {
+            error!("pipe: {}", err);
+            return std::process::ExitCode::from(1)
+        }
+    };
+    let (exit_r, exit_w) = match nix::unistd::pipe() {
+        Ok(fds) => fds,
+        Err(err) => {
+            error!("pipe: {}", err);
+            return std::process::ExitCode::from(1)
+        }
+    };
     match unsafe { nix::unistd::fork() } {
         Ok(nix::unistd::ForkResult::Parent { child }) => {
+            let _ = nix::unistd::close(ready_w);
+            let _ = nix::unistd::close(exit_r);
             let current_pid = nix::unistd::getpid();
             let p_parent_pid = nix::unistd::getppid();
             info!("Parent (PID={}) created child with PID {}", current_pid, child);
+            // wait for child to arm its notification mechanism
+            let mut buf = [0u8; 1];
+            if let Err(err) = nix::unistd::read(ready_r, &mut buf) {
+                error!("read: {}", err);
+                return std::process::ExitCode::from(2)
+            }
             info!("Parent (PID={}; PPID={}) terminating", current_pid, p_parent_pid);
-            return std::process::ExitCode::SUCCESS;
+            match monotonic_ns() {
+                Ok(now) => {
+                    if let Err(err) = nix::unistd::write(exit_w, &now.to_ne_bytes()) {
+                        error!("write: {}", err);
+                    }
+                },
+                Err(err) => error!("clock_gettime: {}", err),
+            }
+            std::process::ExitCode::SUCCESS
         },
         Ok(nix::unistd::ForkResult::Child) => {
-            loop {
-                let ppid = nix::unistd::getppid();
-                if ppid.eq(&ppid_orig) {
-                    std::thread::sleep(time::Duration::from_micros(100000));
-                    continue;
-                } else {
-                    break;
-                }
-            }
+            let _ = nix::unistd::close(ready_r);
+            let _ = nix::unistd::close(exit_w);
             let pid = nix::unistd::getpid();
+            info!("Child (PID={}) waiting for parent death with {:?}", pid, args.mode);
+            let result = match args.mode {
+                Mode::Poll => {
+                    let _ = nix::unistd::write(ready_w, &[1]);
+                    wait_poll(ppid_orig, time::Duration::from_micros(args.interval))
+                },
+                Mode::Pdeathsig => match arm_pdeathsig() {
+                    Ok(mask) => {
+                        let _ = nix::unistd::write(ready_w, &[1]);
+                        wait_pdeathsig(&mask, ppid_orig)
+                    },
+                    Err(err) => Err(err),
+                },
+                Mode::Pidfd => match arm_pidfd(ppid_orig) {
+                    Ok(pidfd) => {
+                        let _ = nix::unistd::write(ready_w, &[1]);
+                        wait_pidfd(pidfd)
+                    },
+                    Err(err) => Err(err),
+                },
+            };
+            let noticed = monotonic_ns();
+            let _ = nix::unistd::close(ready_w);
+            if let Err(err) = result {
+                error!("Child (PID={}) failed to wait for parent with {:?}: {}", pid, args.mode, err);
+                return std::process::ExitCode::from(3)
+            }
             let ppid = nix::unistd::getppid();
             info!("Child (PID={}) now an orphan (parent PID={})", pid, ppid);
+            let mut buf = [0u8; 8];
+            match (nix::unistd::read(exit_r, &mut buf), noticed) {
+                (Ok(8), Ok(noticed)) => {
+                    let exited = u64::from_ne_bytes(buf);
+                    info!("Child (PID={}) noticed parent death after {} us ({:?})",
+                          pid, noticed.saturating_sub(exited) as f64 / 1000.0, args.mode);
+                },
+                _ => warn!("Child (PID={}) could not measure notification latency", pid),
+            }
             std::thread::sleep(time::Duration::from_secs(1));
             info!("Child (PID={}) terminating", pid);
-            return std::process::ExitCode::SUCCESS
+            std::process::ExitCode::SUCCESS
         },
         Err(err) => {
             error!("got error while fork: {}", err);
-            return std::process::ExitCode::from(1)
+            std::process::ExitCode::from(1)
         },
     }
 }
@@ -1,4 +1,7 @@
 fn main () -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("./proto/userns.proto")?;
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("userns_descriptor.bin"))
+        .compile(&["./proto/userns.proto"], &["./proto"])?;
     Ok(())
 }
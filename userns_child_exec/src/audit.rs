@@ -0,0 +1,224 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::idmap::IdRange;
+use crate::policy::{Caller, IdKind};
+use crate::target::Target;
+
+/// Rotated audit logs kept, unless configured
+pub const DEFAULT_KEEP: usize = 5;
+
+/// Parse a size in bytes, with an optional K, M or G suffix
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(format!("invalid size: {}", value)),
+    }
+}
+
+/// What made the server write a map
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Map,
+    Spawn,
+}
+
+/// One line of the audit log
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    /// RFC 3339, UTC
+    pub time: String,
+    pub source: Source,
+    pub sandbox_id: Option<String>,
+    pub caller_pid: i32,
+    pub caller_uid: u32,
+    pub caller_gid: u32,
+    /// Target process and inode of its user namespace
+    pub pid: u32,
+    pub user_ns_inode: Option<u64>,
+    /// `uid_map` or `gid_map`
+    pub map: &'static str,
+    /// Value requested for `setgroups` (GID map only)
+    pub setgroups: Option<&'static str>,
+    pub requested: Vec<IdRange>,
+    /// Content of the map after the attempt, if it could be read
+    pub applied: Option<Vec<IdRange>>,
+    /// `OK` or the gRPC code of the failure
+    pub result: String,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// Record of an attempt by caller, timestamped now
+    ///
+    /// The target's namespace inode and applied map are filled in by
+    /// [`AuditRecord::inspect`], once the target has been pinned.
+    pub fn new(source: Source, caller: &Caller, pid: u32, kind: IdKind, requested: Vec<IdRange>) -> Self {
+        Self {
+            time: prost_types::Timestamp::from(std::time::SystemTime::now()).to_string(),
+            source,
+            sandbox_id: None,
+            caller_pid: caller.pid,
+            caller_uid: caller.uid,
+            caller_gid: caller.gid,
+            pid,
+            user_ns_inode: None,
+            map: match kind {
+                IdKind::Uid => "uid_map",
+                IdKind::Gid => "gid_map",
+            },
+            setgroups: None,
+            requested,
+            applied: None,
+            result: "OK".to_string(),
+            error: None,
+        }
+    }
+
+    /// Fill in the inode of the user namespace the map was written to, as
+    /// checked before writing, and the map read back through the pinned
+    /// target
+    ///
+    /// If the target has exited meanwhile, the map is left out rather than
+    /// read from a process which reused its PID.
+    pub fn inspect(&mut self, target: &Target, user_ns_inode: Option<u64>) {
+        self.user_ns_inode = user_ns_inode;
+        self.applied = target.read(self.map).ok().and_then(|content| crate::idmap::parse_map(&content).ok());
+    }
+
+    pub fn set_result(&mut self, res: &Result<(), tonic::Status>) {
+        if let Err(status) = res {
+            self.result = format!("{:?}", status.code());
+            self.error = Some(status.message().to_string());
+        }
+    }
+}
+
+/// Append-only JSON-lines log of ID map writes, rotated by size
+///
+/// When a record would grow the file past `max_size`, it's renamed to
+/// `PATH.1` (shifting older ones up to `PATH.{keep}`) and a new file is started.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, max_size: u64, keep: usize) -> Self {
+        Self { path, max_size, keep, file: Mutex::new(None) }
+    }
+
+    fn open(&self) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).mode(0o600).open(&self.path)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {},
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))
+    }
+
+    /// Append record as a single line, rotating the log first if needed
+    pub fn write(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("serialize audit record: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        let res = (|| {
+            if file.is_none() {
+                *file = Some(self.open()?);
+            }
+            let size = file.as_ref().unwrap().metadata()?.len();
+            if size > 0 && size + line.len() as u64 > self.max_size {
+                *file = None;
+                self.rotate()?;
+                *file = Some(self.open()?);
+            }
+            let f = file.as_mut().unwrap();
+            f.write_all(&line)?;
+            f.sync_data()
+        })();
+        if let Err(err) = res {
+            // reopened with the next record
+            *file = None;
+            error!("write audit log {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_accepts_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4K"), Ok(4 << 10));
+        assert_eq!(parse_size("10m"), Ok(10 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        for value in ["", "0", "K", "-1K", "1T", "1.5M", "99999999999G"] {
+            assert!(parse_size(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    fn logged_pid(path: &std::path::Path) -> Option<u32> {
+        let content = std::fs::read_to_string(path).ok()?;
+        let record: serde_json::Value = serde_json::from_str(content.lines().last()?).unwrap();
+        record["pid"].as_u64().map(|pid| pid as u32)
+    }
+
+    #[test]
+    fn write_rotates_full_log() {
+        let dir = std::env::temp_dir().join(format!("userns-audit-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let caller = Caller { pid: 1, uid: 1000, gid: 1000 };
+        for keep in [0, 2] {
+            let path = dir.join(format!("audit-{}.log", keep));
+            // every record but the first one starts a new file
+            let log = AuditLog::new(path.clone(), 1, keep);
+            for pid in 1..=4 {
+                log.write(&AuditRecord::new(Source::Map, &caller, pid, IdKind::Uid, vec![]));
+            }
+            let rotated: Vec<Option<u32>> = (1..=3).map(|n| logged_pid(&log.rotated(n))).collect();
+            assert_eq!(logged_pid(&path), Some(4));
+            if keep == 0 {
+                assert_eq!(rotated, vec![None, None, None]);
+            } else {
+                assert_eq!(rotated, vec![Some(3), Some(2), None]);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
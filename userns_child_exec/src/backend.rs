@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::idmap::{self, IdRange};
+use crate::policy::IdKind;
+use crate::target::Target;
+
+/// How the mapper writes ID maps
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Write /proc/PID/uid_map directly (needs CAP_SETUID / CAP_SETGID)
+    Direct,
+    /// Run the setuid newuidmap(1) / newgidmap(1) helpers of shadow-utils
+    Newidmap,
+    /// Only validate and log, write nothing
+    DryRun,
+}
+
+/// Writes the ID maps of a process, after the request passed policy
+///
+/// Implementations block, so they're called from blocking tasks.
+pub trait MapBackend: Send + Sync {
+    /// Write the UID or GID map of target
+    ///
+    /// For GID maps `setgroups` is set up first, as `allow` or `deny`.
+    fn write_map(&self, target: &Target, kind: IdKind, ranges: &[IdRange], allow_setgroups: bool) -> std::io::Result<()>;
+}
+
+/// Backend of the given kind
+pub fn build(kind: BackendKind) -> Arc<dyn MapBackend> {
+    match kind {
+        BackendKind::Direct => Arc::new(Direct),
+        BackendKind::Newidmap => Arc::new(NewIdMap::default()),
+        BackendKind::DryRun => Arc::new(DryRun),
+    }
+}
+
+fn map_file(kind: IdKind) -> &'static str {
+    match kind {
+        IdKind::Uid => "uid_map",
+        IdKind::Gid => "gid_map",
+    }
+}
+
+fn setgroups_value(allow_setgroups: bool) -> &'static str {
+    if allow_setgroups { "allow" } else { "deny" }
+}
+
+/// Writes `/proc/PID/{setgroups,uid_map,gid_map}` itself
+pub struct Direct;
+
+impl MapBackend for Direct {
+    fn write_map(&self, target: &Target, kind: IdKind, ranges: &[IdRange], allow_setgroups: bool) -> std::io::Result<()> {
+        if kind == IdKind::Gid {
+            let setgroups = setgroups_value(allow_setgroups);
+            info!("echo {} >> /proc/{}/setgroups", setgroups, target.pid());
+            target.write("setgroups", setgroups)?;
+        }
+        // whole map has to be written with a single write(2)
+        let content = idmap::format_map(ranges);
+        info!("echo {} >> /proc/{}/{}", content, target.pid(), map_file(kind));
+        target.write(map_file(kind), &content)
+    }
+}
+
+/// Runs newuidmap(1) / newgidmap(1), so the mapper needs no capabilities
+///
+/// The helpers check `/etc/subuid` / `/etc/subgid` of the user running
+/// the mapper, and only map processes of that user. newgidmap leaves
+/// `setgroups` allowed when subordinate GIDs are mapped, so `deny` is
+/// written beforehand when requested.
+///
+/// The helpers take a PID and open `/proc/PID` themselves, so unlike with
+/// Direct the target is only checked to be alive right before they run.
+pub struct NewIdMap {
+    pub newuidmap: PathBuf,
+    pub newgidmap: PathBuf,
+}
+
+impl Default for NewIdMap {
+    fn default() -> Self {
+        Self { newuidmap: PathBuf::from("newuidmap"), newgidmap: PathBuf::from("newgidmap") }
+    }
+}
+
+impl MapBackend for NewIdMap {
+    fn write_map(&self, target: &Target, kind: IdKind, ranges: &[IdRange], allow_setgroups: bool) -> std::io::Result<()> {
+        let helper = match kind {
+            IdKind::Uid => &self.newuidmap,
+            IdKind::Gid => &self.newgidmap,
+        };
+        if kind == IdKind::Gid && !allow_setgroups {
+            if let Err(err) = target.write("setgroups", "deny") {
+                // the helper still denies it when only the own GID is mapped
+                debug!("echo deny >> /proc/{}/setgroups: {}", target.pid(), err);
+            }
+        }
+        target.check_alive()?;
+        let mut command = Command::new(helper);
+        command.arg(target.pid().to_string());
+        for r in ranges {
+            command.args([r.inside.to_string(), r.outside.to_string(), r.length.to_string()]);
+        }
+        info!("run {:?}", command);
+        // errors of the helper itself mustn't look like those of the target
+        let output = command
+            .output()
+            .map_err(|err| std::io::Error::other(format!("run {}: {}", helper.display(), err)))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(std::io::Error::other(format!("{} {}: {}", helper.display(), output.status, stderr.trim())))
+    }
+}
+
+/// Logs what would be written, for trying out policy
+pub struct DryRun;
+
+impl MapBackend for DryRun {
+    fn write_map(&self, target: &Target, kind: IdKind, ranges: &[IdRange], allow_setgroups: bool) -> std::io::Result<()> {
+        if kind == IdKind::Gid {
+            info!("dry run: echo {} >> /proc/{}/setgroups", setgroups_value(allow_setgroups), target.pid());
+        }
+        info!("dry run: echo {} >> /proc/{}/{}", idmap::format_map(ranges), target.pid(), map_file(kind));
+        Ok(())
+    }
+}
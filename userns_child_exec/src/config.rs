@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::backend::BackendKind;
+use crate::limit;
+use crate::metrics::Address;
+use crate::policy::{Policy, Rule};
+use crate::socket::SocketOwner;
+
+/// Mapper configuration, read from a TOML file given with `--config`
+///
+/// ```toml
+/// socket = "/run/userns.sock"
+/// socket_mode = 0o660
+/// socket_owner = "root:userns"
+/// backend = "newidmap"
+/// metrics = "127.0.0.1:9464"
+/// rate_limit = 20.0
+/// max_in_flight = 8
+///
+/// [defaults]
+/// max_length = 65536
+/// max_sandboxes = 4
+///
+/// [users.alice]
+/// uid_ranges = ["300000:65536"]
+/// gid_ranges = ["300000:65536"]
+/// allow_setgroups = true
+///
+/// [groups.ci]
+/// subids = false
+/// max_sandboxes = 32
+/// ```
+///
+/// Command line options take precedence over the file. Only the policy
+/// (`defaults`, `users`, `groups`) is reloaded on SIGHUP, the socket and
+/// backend need a restart.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub socket: Option<PathBuf>,
+    pub socket_mode: Option<u32>,
+    pub socket_owner: Option<SocketOwner>,
+    pub backend: Option<BackendKind>,
+    pub metrics: Option<Address>,
+    /// Mapper requests per second and UID
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<u32>,
+    /// Concurrent mapper requests per UID, not counting Wait and streams
+    pub max_in_flight: Option<usize>,
+    #[serde(default)]
+    pub defaults: Rule,
+    #[serde(default)]
+    pub users: BTreeMap<String, Rule>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, Rule>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|err| format!("read {}: {}", path.display(), err))?;
+        let config: Self = toml::from_str(&content).map_err(|err| format!("parse {}: {}", path.display(), err))?;
+        if let Some(rate) = config.rate_limit {
+            limit::check_rate(rate).map_err(|err| format!("{}: rate_limit: {}", path.display(), err))?;
+        }
+        Ok(config)
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy {
+            defaults: self.defaults.clone(),
+            users: self.users.clone(),
+            groups: self.groups.clone(),
+            ..Policy::default()
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use prost::Message;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+
+/// `google.rpc.Status`, sent in the `grpc-status-details-bin` trailer so
+/// that standard gRPC clients can decode the details
+#[derive(Clone, PartialEq, Message)]
+pub struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(message, repeated, tag = "3")]
+    pub details: Vec<prost_types::Any>,
+}
+
+/// `google.rpc.RetryInfo`
+#[derive(Clone, PartialEq, Message)]
+pub struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    pub retry_delay: Option<prost_types::Duration>,
+}
+
+/// `google.rpc.ErrorInfo`
+#[derive(Clone, PartialEq, Message)]
+pub struct ErrorInfo {
+    #[prost(string, tag = "1")]
+    pub reason: String,
+    #[prost(string, tag = "2")]
+    pub domain: String,
+    #[prost(map = "string, string", tag = "3")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// `google.rpc.BadRequest`
+#[derive(Clone, PartialEq, Message)]
+pub struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub field_violations: Vec<FieldViolation>,
+}
+
+/// `google.rpc.BadRequest.FieldViolation`
+#[derive(Clone, PartialEq, Message)]
+pub struct FieldViolation {
+    #[prost(string, tag = "1")]
+    pub field: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+}
+
+/// Domain of the ErrorInfo details sent by the mapper
+const DOMAIN: &str = "userns.mapper";
+
+fn pack<M: Message>(type_name: &str, message: &M) -> prost_types::Any {
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/{}", type_name),
+        value: message.encode_to_vec(),
+    }
+}
+
+pub fn retry_info(delay: Duration) -> prost_types::Any {
+    let retry_delay = prost_types::Duration { seconds: delay.as_secs() as i64, nanos: delay.subsec_nanos() as i32 };
+    pack("google.rpc.RetryInfo", &RetryInfo { retry_delay: Some(retry_delay) })
+}
+
+pub fn error_info(reason: &str, metadata: HashMap<String, String>) -> prost_types::Any {
+    pack("google.rpc.ErrorInfo", &ErrorInfo { reason: reason.to_string(), domain: DOMAIN.to_string(), metadata })
+}
+
+pub fn bad_request(field: &str, description: &str) -> prost_types::Any {
+    let violation = FieldViolation { field: field.to_string(), description: description.to_string() };
+    pack("google.rpc.BadRequest", &BadRequest { field_violations: vec![violation] })
+}
+
+/// Status carrying details, along with plain metadata for clients which
+/// don't decode them
+pub fn status(code: Code, message: impl Into<String>, details: Vec<prost_types::Any>, metadata: MetadataMap) -> Status {
+    let message = message.into();
+    let rpc_status = RpcStatus { code: code as i32, message: message.clone(), details };
+    Status::with_details_and_metadata(code, message, rpc_status.encode_to_vec().into(), metadata)
+}
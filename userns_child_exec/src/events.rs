@@ -0,0 +1,55 @@
+use tokio::sync::broadcast;
+
+use crate::grpc_handler::userns::{event, Event};
+use crate::policy::Caller;
+
+/// Events buffered for each subscriber before it lags
+const EVENT_CAPACITY: usize = 1024;
+
+/// Fan-out of server events to Events streams
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl EventBus {
+    /// Timestamp and send an event to every subscriber
+    pub fn publish(&self, kind: event::Kind, caller: Option<&Caller>, detail: Option<event::Detail>) {
+        let event = Event {
+            time: Some(prost_types::Timestamp::from(std::time::SystemTime::now())),
+            kind: kind as i32,
+            caller_pid: caller.map_or(0, |c| c.pid as u32),
+            caller_uid: caller.map_or(0, |c| c.uid),
+            caller_gid: caller.map_or(0, |c| c.gid),
+            detail,
+        };
+        // no receivers just means nobody is watching
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+/// Whether caller may see event; only root sees events of other users
+pub fn visible(caller: &Caller, event: &Event) -> bool {
+    caller.uid == 0 || event.caller_uid == caller.uid || event.kind() == event::Kind::ServerShutdown
+}
+
+/// Whether event concerns pid, as its caller or its target
+pub fn involves(event: &Event, pid: u32) -> bool {
+    let target = match &event.detail {
+        Some(event::Detail::Map(map)) => map.pid,
+        Some(event::Detail::Sandbox(sandbox)) => sandbox.pid,
+        None => 0,
+    };
+    event.caller_pid == pid || target == pid
+}
@@ -3,11 +3,48 @@ pub mod userns {
 }
 
 use std::error::Error;
+use std::path::Path;
 
 use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::{Endpoint, Uri};
+
+use crate::idmap::IdRange;
 use userns::userns_mapper_client::UsernsMapperClient;
-use userns::MapRequest;
+use userns::{attach_request, attach_response, AttachRequest, AttachResponse, AttachStart, WindowSize};
+use userns::{Event, EventsRequest};
+use userns::{GetMappingRequest, KillRequest, MapRange, MapRequest, Mapping, Sandbox, SandboxRequest, SpawnRequest, SpawnResponse};
+
+/// Input of an attached client
+#[derive(Debug)]
+pub enum AttachInput {
+    Stdin(Vec<u8>),
+    /// Terminal size as (rows, columns)
+    Resize(u16, u16),
+    CloseStdin,
+    /// Stop attaching, the sandbox keeps running
+    Detach,
+}
+
+impl AttachInput {
+    fn into_request(self) -> Option<AttachRequest> {
+        let frame = match self {
+            AttachInput::Stdin(data) => attach_request::Frame::Stdin(data),
+            AttachInput::Resize(rows, cols) => attach_request::Frame::Resize(WindowSize { rows: rows as u32, cols: cols as u32 }),
+            AttachInput::CloseStdin => attach_request::Frame::CloseStdin(true),
+            AttachInput::Detach => return None,
+        };
+        Some(AttachRequest { frame: Some(frame) })
+    }
+}
+
+pub fn to_map_ranges(ranges: &[IdRange]) -> Vec<MapRange> {
+    ranges
+        .iter()
+        .map(|r| MapRange { id_inside_ns: r.inside, id_outside_ns: r.outside, length: r.length })
+        .collect()
+}
 
 pub struct UsernsClient {
     client: UsernsMapperClient<tonic::transport::Channel>,
@@ -15,21 +52,17 @@ pub struct UsernsClient {
 }
 
 impl UsernsClient {
-    /// Create New Client from Unix Socket ("/tmp/userns.sock")
-    pub fn connect() -> Result<Self, tonic::transport::Error> {
+    /// Create New Client from Unix Socket at socket_path
+    pub fn connect(socket_path: &Path) -> Result<Self, tonic::transport::Error> {
+        let socket_path = socket_path.to_path_buf();
         let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
         let client = rt.block_on(async move {
-            match Endpoint::try_from("http://any.url").
+            Endpoint::try_from("http://any.url").
                 unwrap().
-                connect_with_connector(tower::service_fn(|_: Uri| {
-                    UnixStream::connect("/tmp/userns.sock")
+                connect_with_connector(tower::service_fn(move |_: Uri| {
+                    UnixStream::connect(socket_path.clone())
                 })).
-                await {
-                    Ok(channel) => Ok(channel),
-                    Err(err) => {
-                        return Err(err)
-                    }
-                }
+                await
         });
         match client {
             Ok(client) => {
@@ -51,13 +84,18 @@ impl UsernsClient {
         }
     }
 
-    pub fn map_gid(&mut self, pid: nix::unistd::Pid, uid: nix::unistd::Uid) -> Result<(), Box<dyn Error>> {
+    /// Write several ranges into the UID or GID map of pid at once
+    ///
+    /// A map can only be written once per namespace, so every range has
+    /// to be passed in a single call. allow_setgroups keeps setgroups(2)
+    /// usable in the namespace (GID map only, subject to server policy).
+    pub fn map_ranges(&mut self, pid: nix::unistd::Pid, r#type: userns::map_request::Type, ranges: &[MapRange], allow_setgroups: bool) -> Result<(), Box<dyn Error>> {
         let map_request = tonic::Request::new(MapRequest{
-            r#type: userns::map_request::Type::Gid as i32,
-            id_inside_ns: 0,
-            id_outside_ns: uid.as_raw() as u32,
-            length: 1,
-            pid: pid.as_raw() as u32
+            r#type: r#type as i32,
+            pid: pid.as_raw() as u32,
+            ranges: ranges.to_vec(),
+            allow_setgroups,
+            ..Default::default()
         });
         match self.rt.block_on(self.client.map(map_request)) {
             Ok(_) => Ok(()),
@@ -65,17 +103,115 @@ impl UsernsClient {
         }
     }
 
-    pub fn map_uid(&mut self, pid: nix::unistd::Pid, uid: nix::unistd::Uid) -> Result<(), Box<dyn Error>> {
-        let map_request = tonic::Request::new(MapRequest{
-            r#type: userns::map_request::Type::Uid as i32,
-            id_inside_ns: 0,
-            id_outside_ns: uid.as_raw() as u32,
-            length: 1,
-            pid: pid.as_raw() as u32
-        });
-        match self.rt.block_on(self.client.map(map_request)) {
+    /// Write ranges into the GID map of pid
+    pub fn map_gid(&mut self, pid: nix::unistd::Pid, ranges: &[IdRange]) -> Result<(), Box<dyn Error>> {
+        self.map_ranges(pid, userns::map_request::Type::Gid, &to_map_ranges(ranges), false)
+    }
+
+    /// Write ranges into the UID map of pid
+    pub fn map_uid(&mut self, pid: nix::unistd::Pid, ranges: &[IdRange]) -> Result<(), Box<dyn Error>> {
+        self.map_ranges(pid, userns::map_request::Type::Uid, &to_map_ranges(ranges), false)
+    }
+
+    /// Read current UID / GID mapping of pid's user namespace
+    pub fn get_mapping(&mut self, pid: nix::unistd::Pid) -> Result<Mapping, Box<dyn Error>> {
+        let request = tonic::Request::new(GetMappingRequest { pid: pid.as_raw() as u32 });
+        match self.rt.block_on(self.client.get_mapping(request)) {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(err.to_string().into())
+        }
+    }
+
+    /// Spawn a sandboxed process on the server side
+    pub fn spawn(&mut self, request: SpawnRequest) -> Result<SpawnResponse, Box<dyn Error>> {
+        match self.rt.block_on(self.client.spawn(request)) {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(err.to_string().into())
+        }
+    }
+
+    /// List sandboxes visible to the caller
+    pub fn list(&mut self) -> Result<Vec<Sandbox>, Box<dyn Error>> {
+        match self.rt.block_on(self.client.list(())) {
+            Ok(response) => Ok(response.into_inner().sandboxes),
+            Err(err) => Err(err.to_string().into())
+        }
+    }
+
+    /// Wait until sandbox exits and return its final state
+    pub fn wait(&mut self, sandbox_id: &str) -> Result<Sandbox, Box<dyn Error>> {
+        let request = tonic::Request::new(SandboxRequest { sandbox_id: sandbox_id.to_string() });
+        match self.rt.block_on(self.client.wait(request)) {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(err.to_string().into())
+        }
+    }
+
+    /// Send signal to sandbox (0 means SIGTERM)
+    pub fn kill(&mut self, sandbox_id: &str, signal: i32) -> Result<(), Box<dyn Error>> {
+        let request = tonic::Request::new(KillRequest { sandbox_id: sandbox_id.to_string(), signal });
+        match self.rt.block_on(self.client.kill(request)) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string().into())
+        }
+    }
+
+    /// Forget an exited sandbox
+    pub fn remove(&mut self, sandbox_id: &str) -> Result<(), Box<dyn Error>> {
+        let request = tonic::Request::new(SandboxRequest { sandbox_id: sandbox_id.to_string() });
+        match self.rt.block_on(self.client.remove(request)) {
             Ok(_) => Ok(()),
             Err(err) => Err(err.to_string().into())
         }
     }
+
+    /// Attach to stdio of sandbox until it exits or input sends Detach
+    ///
+    /// Output frames are handed to output as they arrive. Returns the final
+    /// state of the sandbox, or None if detached. logs replays output
+    /// buffered by the server before attaching.
+    pub fn attach(&mut self, sandbox_id: &str, logs: bool, mut input: mpsc::Receiver<AttachInput>, mut output: impl FnMut(attach_response::Frame)) -> Result<Option<Sandbox>, Box<dyn Error>> {
+        let (request_tx, request_rx) = mpsc::channel(64);
+        let start = AttachStart { sandbox_id: sandbox_id.to_string(), logs };
+        request_tx.try_send(AttachRequest { frame: Some(attach_request::Frame::Start(start)) })?;
+        let client = &mut self.client;
+        self.rt.block_on(async move {
+            let mut inbound = client.attach(ReceiverStream::new(request_rx)).await?.into_inner();
+            let mut input_open = true;
+            loop {
+                tokio::select! {
+                    message = inbound.message() => match message? {
+                        Some(AttachResponse { frame: Some(attach_response::Frame::Exit(sandbox)) }) => return Ok(Some(sandbox)),
+                        Some(AttachResponse { frame: Some(frame) }) => output(frame),
+                        Some(_) => {},
+                        None => return Err("attach stream closed by server".into()),
+                    },
+                    received = input.recv(), if input_open => match received.map(AttachInput::into_request) {
+                        Some(Some(request)) => {
+                            if request_tx.send(request).await.is_err() {
+                                input_open = false;
+                            }
+                        },
+                        Some(None) => return Ok(None),
+                        None => input_open = false,
+                    },
+                }
+            }
+        })
+    }
+
+    /// Receive server events until the server shuts down
+    ///
+    /// With pid, only events where it's the caller or the target are sent.
+    pub fn events(&mut self, pid: Option<nix::unistd::Pid>, mut on_event: impl FnMut(Event)) -> Result<(), Box<dyn Error>> {
+        let request = tonic::Request::new(EventsRequest { pid: pid.map_or(0, |p| p.as_raw() as u32) });
+        let client = &mut self.client;
+        self.rt.block_on(async move {
+            let mut stream = client.events(request).await?.into_inner();
+            while let Some(event) = stream.message().await? {
+                on_event(event);
+            }
+            Ok(())
+        })
+    }
 }
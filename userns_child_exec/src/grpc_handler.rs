@@ -1,71 +1,723 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use tonic::{Request, Response, Status};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status, Streaming};
 use userns::userns_mapper_server::UsernsMapper;
-use userns::MapRequest;
+use userns::{attach_request, attach_response, AttachRequest, AttachResponse, WindowSize};
+use userns::{event, Event, EventsRequest, MapEvent};
+use userns::{GetMappingRequest, KillRequest, ListResponse, MapRange, MapRequest, Mapping, SandboxRequest, SpawnRequest, SpawnResponse};
+
+use crate::audit::{self, AuditLog, AuditRecord};
+use crate::backend::{self, BackendKind, MapBackend};
+use crate::details;
+use crate::events::{self, EventBus};
+use crate::idmap::{self, IdRange};
+use crate::metrics::Metrics;
+use crate::policy::{Caller, IdKind, Policy, SharedPolicy};
+use crate::sandbox::{ExitStatus, Sandbox, SandboxManager, SpawnSpec};
+use crate::stdio::{self, Output, SandboxIo};
+use crate::target::Target;
+
+/// Response frames queued per attached client
+const ATTACH_CAPACITY: usize = 64;
+
+/// Events queued per Events stream
+const EVENTS_CAPACITY: usize = 64;
 
 pub mod userns {
     tonic::include_proto!("userns");
 }
 
+/// Descriptors of the userns service, for server reflection
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("userns_descriptor");
+
+/// Ranges requested by MapRequest
+///
+/// Falls back to the single IDInsideNS / IDOutsideNS / Length triple
+/// when no Ranges are given.
+fn requested_ranges(request: &MapRequest) -> Vec<IdRange> {
+    if request.ranges.is_empty() {
+        return vec![IdRange {
+            inside: request.id_inside_ns,
+            outside: request.id_outside_ns,
+            length: request.length,
+        }];
+    }
+    request
+        .ranges
+        .iter()
+        .map(|r| IdRange { inside: r.id_inside_ns, outside: r.id_outside_ns, length: r.length })
+        .collect()
+}
+
+/// Map a request attempts to write, described in the details of its errors
+struct Attempt<'a> {
+    pid: u32,
+    kind: IdKind,
+    ranges: &'a [IdRange],
+}
+
+impl Attempt<'_> {
+    fn file(&self) -> &'static str {
+        match self.kind {
+            IdKind::Uid => "uid_map",
+            IdKind::Gid => "gid_map",
+        }
+    }
+
+    /// Status with an ErrorInfo carrying the map file and its attempted lines
+    fn status(&self, code: tonic::Code, message: String, reason: &str, extra: Vec<prost_types::Any>) -> Status {
+        let metadata = HashMap::from([
+            ("path".to_string(), format!("/proc/{}/{}", self.pid, self.file())),
+            ("map".to_string(), idmap::format_map(self.ranges).trim_end().to_string()),
+        ]);
+        let mut all = vec![details::error_info(reason, metadata)];
+        all.extend(extra);
+        details::status(code, message, all, MetadataMap::new())
+    }
+
+    /// InvalidArgument pointing at field of the request
+    fn invalid(&self, field: &str, message: String) -> Status {
+        let violation = details::bad_request(field, &message);
+        self.status(tonic::Code::InvalidArgument, message, "INVALID_REQUEST", vec![violation])
+    }
+
+    /// Status for a failed write of the map, by the errno the kernel returned
+    fn write_failed(&self, err: &std::io::Error) -> Status {
+        self.failed("write", self.file(), err)
+    }
+
+    /// Status for a failed access to `/proc/PID/{file}`, by errno
+    fn failed(&self, action: &str, file: &str, err: &std::io::Error) -> Status {
+        use nix::errno::Errno;
+        let errno = err.raw_os_error().map(Errno::from_i32);
+        let code = match errno {
+            Some(Errno::EPERM | Errno::EACCES) => tonic::Code::PermissionDenied,
+            Some(Errno::EINVAL) => tonic::Code::InvalidArgument,
+            Some(Errno::ESRCH | Errno::ENOENT) => tonic::Code::NotFound,
+            _ => tonic::Code::Internal,
+        };
+        let reason = errno.map_or_else(|| "WRITE_FAILED".to_string(), |errno| format!("{:?}", errno));
+        self.status(code, format!("{} {} failed: {}", action, file, err), &reason, vec![])
+    }
+}
+
+fn to_map_ranges(ranges: &[IdRange]) -> Vec<MapRange> {
+    ranges
+        .iter()
+        .map(|r| MapRange { id_inside_ns: r.inside, id_outside_ns: r.outside, length: r.length })
+        .collect()
+}
+
+fn from_map_ranges(ranges: &[MapRange]) -> Vec<IdRange> {
+    ranges
+        .iter()
+        .map(|r| IdRange { inside: r.id_inside_ns, outside: r.id_outside_ns, length: r.length })
+        .collect()
+}
+
+fn to_sandbox_info(sandbox: &Sandbox) -> userns::Sandbox {
+    let (running, exit_code, signal) = match sandbox.status() {
+        None => (true, 0, 0),
+        Some(ExitStatus::Exited(code)) => (false, code, 0),
+        Some(ExitStatus::Signaled(signal)) => (false, 0, signal),
+        Some(ExitStatus::Unknown) => (false, -1, 0),
+    };
+    userns::Sandbox {
+        sandbox_id: sandbox.id.clone(),
+        pid: sandbox.pid.as_raw() as u32,
+        argv: sandbox.argv.clone(),
+        owner_uid: sandbox.owner_uid,
+        running,
+        exit_code,
+        signal,
+        tty: sandbox.io.tty,
+    }
+}
+
+fn to_window_size(size: &WindowSize) -> (u16, u16) {
+    let clamp = |value: u32| u16::try_from(value).unwrap_or(u16::MAX);
+    (clamp(size.rows), clamp(size.cols))
+}
+
+fn output_frame(output: Output) -> AttachResponse {
+    let frame = match output {
+        Output::Stdout(data) => attach_response::Frame::Stdout(data),
+        Output::Stderr(data) => attach_response::Frame::Stderr(data),
+    };
+    AttachResponse { frame: Some(frame) }
+}
+
+fn shutting_down() -> Status {
+    Status::new(tonic::Code::Unavailable, "server is shutting down")
+}
+
+/// Ends long-lived streams (Events, Attach, Wait) once the server shuts
+/// down, as they'd hold up draining it otherwise
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    events: EventBus,
+    tx: Arc<watch::Sender<bool>>,
+    sandboxes: Arc<SandboxManager>,
+}
+
+impl ShutdownHandle {
+    pub fn trigger(&self) {
+        self.events.publish(event::Kind::ServerShutdown, None, None);
+        let _ = self.tx.send(true);
+    }
+
+    /// Kill and reap the sandboxes, so none outlives the server
+    pub async fn stop_sandboxes(&self) {
+        self.sandboxes.shutdown().await;
+    }
+}
+
+/// Resolves once shutdown has been triggered
+async fn wait_shutdown(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
+
+/// Forward output of an attached sandbox, ending with its exit status
+async fn attach_output(
+    sandbox: Arc<Sandbox>,
+    logs: Vec<Output>,
+    mut output: broadcast::Receiver<Output>,
+    tx: mpsc::Sender<Result<AttachResponse, Status>>,
+    shutdown: watch::Receiver<bool>,
+) {
+    tokio::select! {
+        _ = forward_output(&sandbox, logs, &mut output, &tx) => {},
+        _ = wait_shutdown(shutdown) => {
+            let _ = tx.send(Err(shutting_down())).await;
+        },
+    }
+}
+
+async fn forward_output(
+    sandbox: &Arc<Sandbox>,
+    logs: Vec<Output>,
+    output: &mut broadcast::Receiver<Output>,
+    tx: &mpsc::Sender<Result<AttachResponse, Status>>,
+) {
+    for frame in logs {
+        if tx.send(Ok(output_frame(frame))).await.is_err() {
+            return;
+        }
+    }
+    let mut drained = sandbox.io.drained();
+    loop {
+        let frame = tokio::select! {
+            frame = output.recv() => frame,
+            _ = drained.wait_for(|drained| *drained) => break,
+        };
+        match frame {
+            Ok(frame) => {
+                if tx.send(Ok(output_frame(frame))).await.is_err() {
+                    // client detached
+                    return;
+                }
+            },
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("attached client of {} is too slow, {} frames skipped", sandbox.id, skipped);
+            },
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    // frames sent before the output was drained are still queued
+    while let Ok(frame) = output.try_recv() {
+        if tx.send(Ok(output_frame(frame))).await.is_err() {
+            return;
+        }
+    }
+    sandbox.wait().await;
+    let exit = attach_response::Frame::Exit(to_sandbox_info(sandbox));
+    let _ = tx.send(Ok(AttachResponse { frame: Some(exit) })).await;
+}
+
+/// Apply input frames of an attached client until it detaches
+async fn attach_input(id: String, io: Arc<SandboxIo>, mut inbound: Streaming<AttachRequest>) {
+    loop {
+        let frame = match inbound.message().await {
+            Ok(Some(AttachRequest { frame: Some(frame) })) => frame,
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(status) => {
+                debug!("attach stream of {} failed: {}", id, status);
+                break;
+            },
+        };
+        let res = match frame {
+            attach_request::Frame::Stdin(data) => io.write_stdin(&data).await,
+            attach_request::Frame::Resize(size) => {
+                let (rows, cols) = to_window_size(&size);
+                io.resize(stdio::window_size(rows, cols)).await
+            },
+            attach_request::Frame::CloseStdin(true) => io.close_stdin().await,
+            attach_request::Frame::CloseStdin(false) => Ok(()),
+            attach_request::Frame::Start(_) => Err(Status::new(tonic::Code::InvalidArgument, "already attached")),
+        };
+        if let Err(status) = res {
+            debug!("attach input of {}: {}", id, status.message());
+        }
+    }
+    debug!("client detached from {}", id);
+}
+
+/// Pin the process a Map or GetMapping request targets
+fn open_target(pid: u32) -> Result<Target, Status> {
+    Target::open(pid).map_err(|err| target_gone(pid, err))
+}
+
+fn target_gone(pid: u32, err: nix::errno::Errno) -> Status {
+    warn!("open process {}: {}", pid, err);
+    match err {
+        nix::errno::Errno::ESRCH | nix::errno::Errno::ENOENT => Status::new(tonic::Code::NotFound, format!("process {} does not exist", pid)),
+        _ => Status::new(tonic::Code::Internal, format!("open process {}: {}", pid, err)),
+    }
+}
+
+/// Maps and setgroups of target's user namespace
+///
+/// Everything is read through the pinned /proc directory, and only
+/// returned if the process is still alive afterwards, so it all belongs
+/// to the same namespace. Blocks, so it's run with spawn_blocking.
+fn read_mapping(target: &Target) -> Result<Mapping, Status> {
+    let pid = target.pid();
+    let read = |name: &str| {
+        target.read(name).map_err(|err| match err.raw_os_error().map(nix::errno::Errno::from_i32) {
+            Some(errno @ (nix::errno::Errno::ESRCH | nix::errno::Errno::ENOENT)) => target_gone(pid, errno),
+            _ => Status::new(tonic::Code::Internal, format!("read /proc/{}/{}: {}", pid, name, err)),
+        })
+    };
+    let parse = |content: String| idmap::parse_map(&content).map_err(|err| Status::new(tonic::Code::Internal, err));
+    let user_ns_inode = target.user_ns_inode().map_err(|err| target_gone(pid, err))?;
+    let uid_map = parse(read("uid_map")?)?;
+    let gid_map = parse(read("gid_map")?)?;
+    let setgroups = read("setgroups")?.trim().to_string();
+    target.check_alive().map_err(|err| target_gone(pid, err))?;
+    Ok(Mapping {
+        user_ns_inode,
+        uid_map: to_map_ranges(&uid_map),
+        gid_map: to_map_ranges(&gid_map),
+        setgroups,
+    })
+}
+
+/// Current map of target, parsed
+fn read_target_map(target: &Target, attempt: &Attempt) -> Result<Vec<IdRange>, Status> {
+    let content = target.read(attempt.file()).map_err(|err| attempt.failed("read", attempt.file(), &err))?;
+    idmap::parse_map(&content).map_err(|err| Status::new(tonic::Code::Internal, err))
+}
+
+/// Write the map of target, if it's still in the user namespace the request
+/// was checked against and that namespace is still unmapped
+///
+/// A map can only be written once, so an identical one counts as success.
+/// Blocks, so it's run with spawn_blocking.
+fn write_unmapped(backend: &dyn MapBackend, target: &Target, user_ns_inode: u64, attempt: &Attempt, allow_setgroups: bool) -> Result<(), Status> {
+    let pid = target.pid();
+    target.check_alive().map_err(|err| target_gone(pid, err))?;
+    if target.user_ns_inode().map_err(|err| target_gone(pid, err))? != user_ns_inode {
+        warn!("process {} changed its user namespace", pid);
+        return Err(Status::new(tonic::Code::FailedPrecondition, format!("process {} changed its user namespace", pid)));
+    }
+    let setgroups_val = if allow_setgroups { "allow" } else { "deny" };
+    let current = read_target_map(target, attempt)?;
+    if current == attempt.ranges {
+        if attempt.kind == IdKind::Gid {
+            let setgroups = target.read("setgroups").map_err(|err| attempt.failed("read", "setgroups", &err))?;
+            if setgroups.trim() != setgroups_val {
+                warn!("/proc/{}/gid_map has already been mapped with other setgroups", pid);
+                return Err(Status::new(tonic::Code::AlreadyExists, "namespace has already been mapped with other setgroups"));
+            }
+        }
+        info!("/proc/{}/{} already has requested mapping", pid, attempt.file());
+        return Ok(());
+    }
+    if !current.is_empty() {
+        warn!("/proc/{}/{} has already been written: {:?}", pid, attempt.file(), current);
+        return Err(Status::new(tonic::Code::AlreadyExists, "namespace has already been mapped"));
+    }
+    // handles UID / GID Mapping
+    if let Err(err) = backend.write_map(target, attempt.kind, attempt.ranges, allow_setgroups) {
+        warn!("write /proc/{}/{} failed: {}", pid, attempt.file(), err);
+        // someone else may have written the same map in the meantime
+        if read_target_map(target, attempt)? == attempt.ranges {
+            return Ok(());
+        }
+        return Err(attempt.write_failed(&err));
+    }
+    Ok(())
+}
+
+/// What apply_map established about a Map request, for metrics and auditing
 #[derive(Default)]
-pub struct UsernsMapperImpl {}
+struct Checked {
+    /// Map type, once it has been validated
+    kind: Option<IdKind>,
+    /// Target and the inode of the user namespace it was checked against,
+    /// once the write has been attempted
+    pinned: Option<(Target, u64)>,
+}
+
+pub struct UsernsMapperImpl {
+    policy: SharedPolicy,
+    sandboxes: Arc<SandboxManager>,
+    events: EventBus,
+    audit: Option<Arc<AuditLog>>,
+    backend: Arc<dyn MapBackend>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Default for UsernsMapperImpl {
+    fn default() -> Self {
+        Self::new(Policy::default(), None, backend::build(BackendKind::Direct))
+    }
+}
+
+impl UsernsMapperImpl {
+    /// Mapper enforcing policy, writing maps with backend, and recording
+    /// them in audit if given
+    pub fn new(policy: Policy, audit: Option<AuditLog>, backend: Arc<dyn MapBackend>) -> Self {
+        let events = EventBus::default();
+        let audit = audit.map(Arc::new);
+        let metrics = Arc::new(Metrics::default());
+        Self {
+            policy: SharedPolicy::new(policy),
+            sandboxes: Arc::new(SandboxManager::new(events.clone(), audit.clone(), backend.clone(), metrics.clone())),
+            events,
+            audit,
+            backend,
+            metrics,
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Handle to replace the policy, e.g. when the configuration is reloaded
+    pub fn policy_handle(&self) -> SharedPolicy {
+        self.policy.clone()
+    }
+
+    /// Counters of the mapper, for the metrics endpoint
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Handle to end long-lived streams when the server shuts down
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { events: self.events.clone(), tx: self.shutdown.clone(), sandboxes: self.sandboxes.clone() }
+    }
+
+    /// Check and write the mapping requested by caller, filling in checked
+    /// as it goes
+    async fn apply_map(&self, caller: &Caller, req: &MapRequest, checked: &mut Checked) -> Result<(), Status> {
+        let kind = match userns::map_request::Type::from_i32(req.r#type) {
+            Some(userns::map_request::Type::Gid) => IdKind::Gid,
+            Some(userns::map_request::Type::Uid) => IdKind::Uid,
+            None => {
+                let message = format!("unknown map type {}", req.r#type);
+                let violation = details::bad_request("type", &message);
+                return Err(details::status(tonic::Code::InvalidArgument, message, vec![violation], MetadataMap::new()));
+            },
+        };
+        checked.kind = Some(kind);
+        let ranges = requested_ranges(req);
+        let attempt = Attempt { pid: req.pid, kind, ranges: &ranges };
+        if req.pid == 0 {
+            return Err(attempt.invalid("PID", "PID must not be 0".to_string()));
+        }
+        if i32::try_from(req.pid).is_err() {
+            return Err(attempt.invalid("PID", format!("PID {} is out of range", req.pid)));
+        }
+        for (i, range) in ranges.iter().enumerate() {
+            if let Err((field, err)) = range.check() {
+                // without Ranges, the single range is given by the top-level fields
+                let field = if req.ranges.is_empty() { field.to_string() } else { format!("Ranges[{}].{}", i, field) };
+                return Err(attempt.invalid(&field, err));
+            }
+        }
+        if let Err(err) = idmap::validate(&ranges) {
+            return Err(attempt.invalid("Ranges", err));
+        }
+        let target = open_target(req.pid)?;
+        let policy = self.policy.get();
+        let allowed = policy
+            .check_target(caller, &target)
+            .and_then(|_| ranges.iter().try_for_each(|range| policy.check_range(caller, kind, range.outside, range.length)));
+        if let Err(status) = allowed {
+            warn!("deny {:?} mapping for caller {:?}: {}", kind, caller, status.message());
+            self.metrics.denied("map");
+            return Err(status);
+        }
+        let allow_setgroups = req.allow_setgroups;
+        if allow_setgroups {
+            if kind != IdKind::Gid {
+                return Err(attempt.invalid("AllowSetgroups", "AllowSetgroups is only valid for GID mapping".to_string()));
+            }
+            if !policy.may_allow_setgroups(caller, &ranges) {
+                warn!("deny setgroups allow for caller {:?}", caller);
+                self.metrics.denied("map");
+                return Err(Status::new(tonic::Code::PermissionDenied, "setgroups allow is not permitted for this GID map"));
+            }
+        }
+        // the process tree and owner were read through /proc/PID, which
+        // must still be the target's
+        target.check_alive().map_err(|err| target_gone(req.pid, err))?;
+        let user_ns_inode = target.user_ns_inode().map_err(|err| target_gone(req.pid, err))?;
+        let backend = self.backend.clone();
+        let ranges = ranges.clone();
+        let (target, res) = tokio::task::spawn_blocking(move || {
+            let attempt = Attempt { pid: target.pid(), kind, ranges: &ranges };
+            let res = write_unmapped(backend.as_ref(), &target, user_ns_inode, &attempt, allow_setgroups);
+            (target, res)
+        })
+        .await
+        .map_err(|err| Status::new(tonic::Code::Internal, format!("map task: {}", err)))?;
+        checked.pinned = Some((target, user_ns_inode));
+        res
+    }
+
+    /// Mapping of a sandbox, checked against policy like Map requests
+    ///
+    /// Without explicit ranges the caller's own ID is mapped to root.
+    fn sandbox_map(&self, policy: &Policy, caller: &Caller, kind: IdKind, ranges: &[MapRange]) -> Result<Vec<IdRange>, Status> {
+        let ranges = if ranges.is_empty() {
+            let own = match kind {
+                IdKind::Uid => caller.uid,
+                IdKind::Gid => caller.gid,
+            };
+            vec![IdRange { inside: 0, outside: own, length: 1 }]
+        } else {
+            from_map_ranges(ranges)
+        };
+        if let Err(err) = idmap::validate(&ranges) {
+            return Err(Status::new(tonic::Code::InvalidArgument, err));
+        }
+        for range in &ranges {
+            if let Err(status) = policy.check_range(caller, kind, range.outside, range.length) {
+                warn!("deny {:?} mapping for caller {:?}: {}", kind, caller, status.message());
+                self.metrics.denied("spawn");
+                return Err(status);
+            }
+        }
+        Ok(ranges)
+    }
+}
 
 #[tonic::async_trait]
 impl UsernsMapper for UsernsMapperImpl {
+    type AttachStream = ReceiverStream<Result<AttachResponse, Status>>;
+    type EventsStream = ReceiverStream<Result<Event, Status>>;
+
     /// handles ping request
     async fn ping(&self, _request: Request<()>) -> Result<Response<()>, Status> {
         Ok(Response::new(()))
     }
     /// handles uid / gid mapping request
     async fn map(&self, request: Request<MapRequest>) -> Result<Response<()>, Status> {
-        if request.get_ref().pid == 0 {
-            return Err(Status::new(tonic::Code::InvalidArgument, "invalid id_outside_ns"));
-        }
-        let pid_directory = Path::new("/proc").join(Path::new(&request.get_ref().pid.to_string()));
-        info!("check directory is exists: {}", pid_directory.to_str().unwrap());
-        if !pid_directory.exists() {
-            warn!("directory does not found: {}", pid_directory.to_str().unwrap());
-            return Err(Status::new(tonic::Code::NotFound, "process does not found"));
-        }
-        // handles UID / GID Mapping
-        match request.get_ref().r#type() {
-            userns::map_request::Type::Gid => {
-                let setgroups_path = pid_directory.join("setgroups");
-                info!("echo deny >> {}", setgroups_path.to_str().unwrap());
-                if let Err(err) = tokio::fs::write(setgroups_path.clone(), b"deny").await {
-                    warn!("echo deny >> {} failed: {}", setgroups_path.to_str().unwrap(), err);
-                    return Err(Status::new(tonic::Code::Internal, "setgroups failed"));
+        let caller = Caller::from_request(&request)?;
+        let req = request.into_inner();
+        let detail = |reason: String| {
+            Some(event::Detail::Map(MapEvent {
+                r#type: req.r#type,
+                pid: req.pid,
+                ranges: to_map_ranges(&requested_ranges(&req)),
+                allow_setgroups: req.allow_setgroups,
+                reason,
+            }))
+        };
+        self.events.publish(event::Kind::MapRequested, Some(&caller), detail(String::new()));
+        let started = std::time::Instant::now();
+        let mut checked = Checked::default();
+        let res = self.apply_map(&caller, &req, &mut checked).await;
+        self.metrics.record_map(checked.kind, res.as_ref().err().map_or(tonic::Code::Ok, |status| status.code()), started.elapsed());
+        let kind = match &res {
+            Ok(()) => event::Kind::MapApplied,
+            Err(status) if status.code() == tonic::Code::PermissionDenied => event::Kind::MapDenied,
+            Err(_) => event::Kind::MapFailed,
+        };
+        let reason = res.as_ref().err().map(|status| status.message().to_string()).unwrap_or_default();
+        self.events.publish(kind, Some(&caller), detail(reason));
+        // without a valid type, there's no map the request could have written
+        if let (Some(audit), Some(id_kind)) = (self.audit.clone(), checked.kind) {
+            let mut record = AuditRecord::new(audit::Source::Map, &caller, req.pid, id_kind, requested_ranges(&req));
+            if id_kind == IdKind::Gid {
+                record.setgroups = Some(if req.allow_setgroups { "allow" } else { "deny" });
+            }
+            record.set_result(&res);
+            let _ = tokio::task::spawn_blocking(move || {
+                if let Some((target, user_ns_inode)) = &checked.pinned {
+                    record.inspect(target, Some(*user_ns_inode));
                 }
-                let write_val = format!("{} {} {}\n", 
-                                        request.get_ref().id_inside_ns.to_string(),
-                                        request.get_ref().id_outside_ns.to_string(),
-                                        request.get_ref().length.to_string()
-                                        );
-                let gid_map_path = pid_directory.join("gid_map");
-                info!("echo {} >> {}", write_val.as_str(), gid_map_path.to_str().unwrap());
-                if let Err(err) = tokio::fs::write(gid_map_path.clone(), write_val.clone()).await {
-                    warn!("echo {} >> {} failed: {}", write_val.as_str(), gid_map_path.to_str().unwrap(), err);
-                    return Err(Status::new(tonic::Code::Internal, "gid_map write failed"));
+                audit.write(&record)
+            })
+            .await;
+        }
+        res.map(Response::new)
+    }
+    /// handles mapping query, for processes the caller may map
+    async fn get_mapping(&self, request: Request<GetMappingRequest>) -> Result<Response<Mapping>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let pid = request.get_ref().pid;
+        if pid == 0 || i32::try_from(pid).is_err() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "invalid pid"));
+        }
+        let target = open_target(pid)?;
+        if let Err(status) = self.policy.get().check_target(&caller, &target) {
+            warn!("deny mapping query for caller {:?}: {}", caller, status.message());
+            self.metrics.denied("get_mapping");
+            return Err(status);
+        }
+        let mapping = tokio::task::spawn_blocking(move || read_mapping(&target))
+            .await
+            .map_err(|err| Status::new(tonic::Code::Internal, format!("mapping task: {}", err)))??;
+        Ok(Response::new(mapping))
+    }
+    /// handles sandbox spawn request
+    async fn spawn(&self, request: Request<SpawnRequest>) -> Result<Response<SpawnResponse>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let req = request.into_inner();
+        if req.argv.is_empty() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "empty argv"));
+        }
+        let mut flags = nix::sched::CloneFlags::empty();
+        for (enabled, flag) in [
+            (req.ipc, nix::sched::CloneFlags::CLONE_NEWIPC),
+            (req.mount, nix::sched::CloneFlags::CLONE_NEWNS),
+            (req.network, nix::sched::CloneFlags::CLONE_NEWNET),
+            (req.pid, nix::sched::CloneFlags::CLONE_NEWPID),
+            (req.uts, nix::sched::CloneFlags::CLONE_NEWUTS),
+            (req.user, nix::sched::CloneFlags::CLONE_NEWUSER),
+        ] {
+            if enabled {
+                flags |= flag;
+            }
+        }
+        let policy = self.policy.get();
+        let (uid_map, gid_map) = if req.user {
+            let uid_map = self.sandbox_map(&policy, &caller, IdKind::Uid, &req.uid_map)?;
+            let gid_map = self.sandbox_map(&policy, &caller, IdKind::Gid, &req.gid_map)?;
+            if req.allow_setgroups && !policy.may_allow_setgroups(&caller, &gid_map) {
+                warn!("deny setgroups allow for caller {:?}", caller);
+                self.metrics.denied("spawn");
+                return Err(Status::new(tonic::Code::PermissionDenied, "setgroups allow is not permitted for this GID map"));
+            }
+            (uid_map, gid_map)
+        } else {
+            if !req.uid_map.is_empty() || !req.gid_map.is_empty() || req.allow_setgroups {
+                return Err(Status::new(tonic::Code::InvalidArgument, "mappings require a user namespace"));
+            }
+            (vec![], vec![])
+        };
+        let mut env: Vec<(String, String)> = req.env.into_iter().collect();
+        env.sort();
+        let spec = SpawnSpec {
+            flags,
+            argv: req.argv,
+            env,
+            cwd: req.cwd,
+            uid_map,
+            gid_map,
+            allow_setgroups: req.allow_setgroups,
+            tty: req.tty,
+            window_size: req.window_size.as_ref().map(to_window_size),
+        };
+        let sandbox = match self.sandboxes.spawn(caller, spec, policy.max_sandboxes(&caller)).await {
+            Ok(sandbox) => sandbox,
+            Err(status) => {
+                if status.code() == tonic::Code::ResourceExhausted {
+                    self.metrics.denied("spawn");
                 }
-                return Ok(Response::new(()))
+                return Err(status);
             },
-            userns::map_request::Type::Uid => {
-                let write_val = format!("{} {} {}\n", 
-                                        request.get_ref().id_inside_ns.to_string(),
-                                        request.get_ref().id_outside_ns.to_string(),
-                                        request.get_ref().length.to_string()
-                                        );
-                let gid_map_path = pid_directory.join("uid_map");
-                info!("echo {} >> {}", write_val.as_str(), gid_map_path.to_str().unwrap());
-                if let Err(err) = tokio::fs::write(gid_map_path.clone(), write_val.clone()).await {
-                    warn!("echo {} >> {} failed: {}", write_val.as_str(), gid_map_path.to_str().unwrap(), err);
-                    return Err(Status::new(tonic::Code::Internal, "uid_map write failed"));
+        };
+        Ok(Response::new(SpawnResponse { sandbox_id: sandbox.id.clone(), pid: sandbox.pid.as_raw() as u32 }))
+    }
+    /// handles sandbox list request
+    async fn list(&self, request: Request<()>) -> Result<Response<ListResponse>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let sandboxes = self.sandboxes.list(&caller).iter().map(|s| to_sandbox_info(s)).collect();
+        Ok(Response::new(ListResponse { sandboxes }))
+    }
+    /// handles sandbox wait request
+    async fn wait(&self, request: Request<SandboxRequest>) -> Result<Response<userns::Sandbox>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let sandbox = self.sandboxes.get(&caller, &request.get_ref().sandbox_id)?;
+        tokio::select! {
+            _ = sandbox.wait() => Ok(Response::new(to_sandbox_info(&sandbox))),
+            _ = wait_shutdown(self.shutdown.subscribe()) => Err(shutting_down()),
+        }
+    }
+    /// handles sandbox kill request
+    async fn kill(&self, request: Request<KillRequest>) -> Result<Response<()>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let signal = match request.get_ref().signal {
+            0 => nix::sys::signal::Signal::SIGTERM,
+            signal => nix::sys::signal::Signal::try_from(signal)
+                .map_err(|_| Status::new(tonic::Code::InvalidArgument, "invalid signal"))?,
+        };
+        let sandbox = self.sandboxes.get(&caller, &request.get_ref().sandbox_id)?;
+        info!("send {} to sandbox {}", signal, sandbox.id);
+        sandbox.kill(signal)?;
+        Ok(Response::new(()))
+    }
+    /// handles sandbox remove request
+    async fn remove(&self, request: Request<SandboxRequest>) -> Result<Response<()>, Status> {
+        let caller = Caller::from_request(&request)?;
+        self.sandboxes.remove(&caller, &request.get_ref().sandbox_id)?;
+        Ok(Response::new(()))
+    }
+    /// handles attach request
+    ///
+    /// The first frame selects the sandbox, output is streamed back until
+    /// it exits; input runs independently until the client detaches.
+    async fn attach(&self, request: Request<Streaming<AttachRequest>>) -> Result<Response<Self::AttachStream>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let mut inbound = request.into_inner();
+        let start = match inbound.message().await? {
+            Some(AttachRequest { frame: Some(attach_request::Frame::Start(start)) }) => start,
+            _ => return Err(Status::new(tonic::Code::InvalidArgument, "first frame must be Start")),
+        };
+        let sandbox = self.sandboxes.get(&caller, &start.sandbox_id)?;
+        info!("caller {:?} attached to {}", caller, sandbox.id);
+        let (logs, output) = sandbox.io.subscribe(start.logs);
+        let (tx, rx) = mpsc::channel(ATTACH_CAPACITY);
+        tokio::spawn(attach_input(sandbox.id.clone(), sandbox.io.clone(), inbound));
+        tokio::spawn(attach_output(sandbox, logs, output, tx, self.shutdown.subscribe()));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+    /// handles events subscription
+    async fn events(&self, request: Request<EventsRequest>) -> Result<Response<Self::EventsStream>, Status> {
+        let caller = Caller::from_request(&request)?;
+        let pid = request.get_ref().pid;
+        let mut subscription = self.events.subscribe();
+        let (tx, rx) = mpsc::channel(EVENTS_CAPACITY);
+        debug!("caller {:?} subscribed to events", caller);
+        tokio::spawn(async move {
+            loop {
+                let event = match subscription.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // audit consumers must know the stream has gaps
+                        let _ = tx.send(Err(Status::new(tonic::Code::DataLoss, format!("{} events dropped", skipped)))).await;
+                        break;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let shutdown = event.kind() == event::Kind::ServerShutdown;
+                if events::visible(&caller, &event) && (pid == 0 || shutdown || events::involves(&event, pid)) && tx.send(Ok(event)).await.is_err() {
+                    break;
+                }
+                if shutdown {
+                    break;
                 }
-                return Ok(Response::new(()))
             }
-        };
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
-
-
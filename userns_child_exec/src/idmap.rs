@@ -0,0 +1,220 @@
+use serde::Serialize;
+
+use crate::policy::SubIdRange;
+
+/// Maximum number of lines the kernel accepts in `uid_map` / `gid_map`
+/// (since Linux 4.15)
+pub const MAX_MAP_LINES: usize = 340;
+
+/// Writes to `uid_map` / `gid_map` have to be smaller than a page
+const MAX_MAP_SIZE: usize = 4096;
+
+/// Single line of `uid_map` / `gid_map`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub inside: u32,
+    pub outside: u32,
+    pub length: u32,
+}
+
+impl IdRange {
+    /// Check that the range is non-empty and stays within the ID space
+    ///
+    /// The kernel rejects ranges whose end wraps around, which also keeps
+    /// out ID 4294967295 (`(uid_t) -1`). On error the offending field is
+    /// returned along with the message.
+    pub fn check(&self) -> Result<(), (&'static str, String)> {
+        if self.length == 0 {
+            return Err(("Length", "length is zero".to_string()));
+        }
+        for (field, start) in [("IDInsideNS", self.inside), ("IDOutsideNS", self.outside)] {
+            if start as u64 + self.length as u64 > u32::MAX as u64 {
+                return Err((field, format!("{}+{} goes past ID {}", start, self.length, u32::MAX - 1)));
+            }
+        }
+        Ok(())
+    }
+
+    fn overlaps(start_a: u32, start_b: u32, length_a: u32, length_b: u32) -> bool {
+        let end_a = start_a as u64 + length_a as u64;
+        let end_b = start_b as u64 + length_b as u64;
+        (start_a as u64) < end_b && (start_b as u64) < end_a
+    }
+}
+
+/// Check ranges in the same way as the kernel does before writing them
+///
+/// Every range has to be non-empty and within the ID space, and neither
+/// the inside nor the outside ranges may overlap each other.
+pub fn validate(ranges: &[IdRange]) -> Result<(), String> {
+    if ranges.is_empty() {
+        return Err("no range given".to_string());
+    }
+    if ranges.len() > MAX_MAP_LINES {
+        return Err(format!("too many ranges: {} (max {})", ranges.len(), MAX_MAP_LINES));
+    }
+    for (i, a) in ranges.iter().enumerate() {
+        if let Err((_, err)) = a.check() {
+            return Err(format!("range #{}: {}", i, err));
+        }
+        for (j, b) in ranges.iter().enumerate().skip(i + 1) {
+            if IdRange::overlaps(a.inside, b.inside, a.length, b.length) {
+                return Err(format!("inside ranges #{} and #{} overlap", i, j));
+            }
+            if IdRange::overlaps(a.outside, b.outside, a.length, b.length) {
+                return Err(format!("outside ranges #{} and #{} overlap", i, j));
+            }
+        }
+    }
+    if format_map(ranges).len() >= MAX_MAP_SIZE {
+        return Err(format!("map content exceeds {} bytes", MAX_MAP_SIZE));
+    }
+    Ok(())
+}
+
+/// Render ranges as content of `uid_map` / `gid_map`
+///
+/// The whole content has to be written with a single write(2).
+pub fn format_map(ranges: &[IdRange]) -> String {
+    ranges
+        .iter()
+        .map(|r| format!("{} {} {}\n", r.inside, r.outside, r.length))
+        .collect()
+}
+
+/// Parse content of `uid_map` / `gid_map`
+pub fn parse_map(content: &str) -> Result<Vec<IdRange>, String> {
+    let mut ranges = vec![];
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let parse = |s: &str| s.parse::<u32>().map_err(|err| format!("malformed map line {:?}: {}", line, err));
+        if fields.len() != 3 {
+            return Err(format!("malformed map line: {:?}", line));
+        }
+        ranges.push(IdRange { inside: parse(fields[0])?, outside: parse(fields[1])?, length: parse(fields[2])? });
+    }
+    Ok(ranges)
+}
+
+/// Parse a range given as `inside:outside:length`
+pub fn parse_range(value: &str) -> Result<IdRange, String> {
+    let fields: Vec<&str> = value.split(':').collect();
+    if fields.len() != 3 {
+        return Err(format!("expected inside:outside:length, got {:?}", value));
+    }
+    let parse = |s: &str| s.parse::<u32>().map_err(|err| format!("invalid ID {:?}: {}", s, err));
+    Ok(IdRange { inside: parse(fields[0])?, outside: parse(fields[1])?, length: parse(fields[2])? })
+}
+
+/// Map the inside IDs not covered by ranges yet, lowest first, to the
+/// subordinate IDs in order
+///
+/// This is how rootless containers use `/etc/subuid`: with the own ID
+/// mapped to 0, the subordinate IDs become 1, 2, ... inside.
+pub fn fill_unmapped(ranges: &mut Vec<IdRange>, subids: &[SubIdRange]) {
+    let taken = ranges.clone();
+    let mut cursor = 0u64;
+    for subid in subids {
+        let mut outside = subid.start as u64;
+        let mut remaining = subid.count as u64;
+        while remaining > 0 && cursor < u32::MAX as u64 {
+            if let Some(r) = taken.iter().find(|r| r.inside as u64 <= cursor && cursor < r.inside as u64 + r.length as u64) {
+                cursor = r.inside as u64 + r.length as u64;
+                continue;
+            }
+            let gap_end = taken
+                .iter()
+                .map(|r| r.inside as u64)
+                .filter(|&start| start > cursor)
+                .min()
+                .unwrap_or(u32::MAX as u64);
+            let length = remaining.min(gap_end - cursor);
+            ranges.push(IdRange { inside: cursor as u32, outside: outside as u32, length: length as u32 });
+            cursor += length;
+            outside += length;
+            remaining -= length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(inside: u32, outside: u32, length: u32) -> IdRange {
+        IdRange { inside, outside, length }
+    }
+
+    #[test]
+    fn check_names_the_field() {
+        assert_eq!(range(0, 1000, 0).check().unwrap_err().0, "Length");
+        assert_eq!(range(u32::MAX, 1000, 1).check().unwrap_err().0, "IDInsideNS");
+        assert_eq!(range(0, u32::MAX - 1, 2).check().unwrap_err().0, "IDOutsideNS");
+        assert_eq!(range(u32::MAX - 1, u32::MAX - 1, 1).check(), Ok(()));
+        assert_eq!(range(1, 0, u32::MAX - 1).check(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_overlaps() {
+        assert_eq!(validate(&[]).unwrap_err(), "no range given");
+        assert_eq!(validate(&[range(0, 1000, 1), range(1, 1001, 10)]), Ok(()));
+        assert_eq!(validate(&[range(0, 1000, 2), range(1, 2000, 1)]).unwrap_err(), "inside ranges #0 and #1 overlap");
+        assert_eq!(validate(&[range(0, 1000, 1), range(5, 990, 11)]).unwrap_err(), "outside ranges #0 and #1 overlap");
+        assert_eq!(validate(&[range(0, 0, 1), range(1, 1, u32::MAX)]).unwrap_err(), format!("range #1: 1+{} goes past ID {}", u32::MAX, u32::MAX - 1));
+    }
+
+    #[test]
+    fn validate_limits_lines_and_size() {
+        let ranges: Vec<IdRange> = (0..MAX_MAP_LINES as u32).map(|i| range(i, i, 1)).collect();
+        assert_eq!(validate(&ranges), Ok(()));
+        let ranges: Vec<IdRange> = (0..MAX_MAP_LINES as u32 + 1).map(|i| range(i, i, 1)).collect();
+        assert_eq!(validate(&ranges).unwrap_err(), "too many ranges: 341 (max 340)");
+        // 24 bytes per line
+        let ranges: Vec<IdRange> = (0..200).map(|i| range(4000000000 + i, 4000000000 + i, 1)).collect();
+        assert_eq!(validate(&ranges).unwrap_err(), "map content exceeds 4096 bytes");
+    }
+
+    #[test]
+    fn parse_map_reads_kernel_format() {
+        let content = "         0       1000          1\n         1     100000      65536\n";
+        assert_eq!(parse_map(content), Ok(vec![range(0, 1000, 1), range(1, 100000, 65536)]));
+        assert_eq!(parse_map(""), Ok(vec![]));
+        assert_eq!(parse_map(&format_map(&[range(0, 1000, 1)])), Ok(vec![range(0, 1000, 1)]));
+        assert!(parse_map("0 1000\n").is_err());
+        assert!(parse_map("0 1000 x\n").is_err());
+        assert!(parse_map("0 1000 4294967296\n").is_err());
+    }
+
+    #[test]
+    fn parse_range_needs_three_ids() {
+        assert_eq!(parse_range("0:1000:1"), Ok(range(0, 1000, 1)));
+        assert!(parse_range("0:1000").is_err());
+        assert!(parse_range("0:-1:1").is_err());
+    }
+
+    #[test]
+    fn fill_unmapped_skips_mapped_ids() {
+        let mut ranges = vec![range(0, 1000, 1)];
+        fill_unmapped(&mut ranges, &[SubIdRange { start: 100000, count: 65536 }]);
+        assert_eq!(ranges, vec![range(0, 1000, 1), range(1, 100000, 65536)]);
+
+        let mut ranges = vec![range(0, 1000, 1), range(5, 2000, 1)];
+        fill_unmapped(&mut ranges, &[SubIdRange { start: 100000, count: 4 }, SubIdRange { start: 200000, count: 6 }]);
+        assert_eq!(
+            ranges,
+            vec![range(0, 1000, 1), range(5, 2000, 1), range(1, 100000, 4), range(6, 200000, 6)]
+        );
+        assert_eq!(validate(&ranges), Ok(()));
+    }
+
+    #[test]
+    fn fill_unmapped_stops_at_end_of_id_space() {
+        let mut ranges = vec![range(0, 0, u32::MAX - 1)];
+        fill_unmapped(&mut ranges, &[SubIdRange { start: u32::MAX - 1, count: 10 }]);
+        assert_eq!(ranges, vec![range(0, 0, u32::MAX - 1), range(u32::MAX - 1, u32::MAX - 1, 1)]);
+        assert_eq!(validate(&ranges), Ok(()));
+    }
+}
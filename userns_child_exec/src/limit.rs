@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request, Response};
+use tonic::body::BoxBody;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::server::UdsConnectInfo;
+use tower::{Layer, Service};
+
+use crate::details;
+
+/// Only requests to the mapper are limited, not health checks or reflection
+const LIMITED_PREFIX: &str = "/userns.UsernsMapper/";
+
+/// RPCs which may legitimately stay in flight for long, and so don't
+/// count against the in-flight cap
+///
+/// The slot of a request is given back once its response headers are sent,
+/// so streaming RPCs couldn't be capped anyway; their calls are still rate
+/// limited.
+const LONG_RUNNING: &[&str] = &["/userns.UsernsMapper/Wait", "/userns.UsernsMapper/Attach", "/userns.UsernsMapper/Events"];
+
+/// Suggested delay when the in-flight cap is reached
+const IN_FLIGHT_RETRY: Duration = Duration::from_millis(100);
+
+/// Buckets kept before full ones are dropped
+const MAX_BUCKETS: usize = 1024;
+
+/// Limits applied to each caller UID
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Requests per second, None for no rate limit
+    pub rate: Option<f64>,
+    /// Requests allowed at once before the rate applies (default: rate, at least 1)
+    pub burst: Option<u32>,
+    /// Requests handled concurrently, None for no cap (Wait and streams
+    /// aren't counted)
+    pub max_in_flight: Option<usize>,
+}
+
+/// Check a rate in requests per second, which must be finite and positive
+pub fn check_rate(rate: f64) -> Result<f64, String> {
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!("rate must be a positive number of requests per second: {}", rate))
+    }
+}
+
+/// Parse a rate in requests per second
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate = value.parse::<f64>().map_err(|_| format!("invalid rate: {}", value))?;
+    check_rate(rate)
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.rate.is_none() && self.max_in_flight.is_none()
+    }
+
+    fn capacity(&self, rate: f64) -> f64 {
+        self.burst.map_or(rate.ceil().max(1.0), |burst| burst.max(1) as f64)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets and in-flight counts per UID
+struct Limiter {
+    limits: Limits,
+    buckets: Mutex<HashMap<u32, Bucket>>,
+    in_flight: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+/// In-flight slot of a UID, given back when dropped
+struct Permit {
+    uid: u32,
+    in_flight: Option<Arc<Mutex<HashMap<u32, usize>>>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(in_flight) = &self.in_flight {
+            let mut in_flight = in_flight.lock().unwrap();
+            if let Some(count) = in_flight.get_mut(&self.uid) {
+                *count -= 1;
+                if *count == 0 {
+                    in_flight.remove(&self.uid);
+                }
+            }
+        }
+    }
+}
+
+fn exhausted(message: String, retry_after: Duration) -> tonic::Status {
+    let mut metadata = MetadataMap::new();
+    // whole seconds, like the HTTP header, for clients which skip the details
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    metadata.insert("retry-after", MetadataValue::from(seconds));
+    details::status(tonic::Code::ResourceExhausted, message, vec![details::retry_info(retry_after)], metadata)
+}
+
+impl Limiter {
+    /// Take a token of uid's bucket
+    fn take_token(&self, uid: u32, rate: f64) -> Result<(), tonic::Status> {
+        let capacity = self.limits.capacity(rate);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            // full buckets are the same as missing ones
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity);
+        }
+        let bucket = buckets.entry(uid).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let retry_after = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+            return Err(exhausted(format!("rate limit of {} requests per second exceeded for UID {}", rate, uid), retry_after));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Admit a request of uid, or tell when to retry
+    fn admit(&self, uid: u32, long_running: bool) -> Result<Permit, tonic::Status> {
+        if let Some(rate) = self.limits.rate {
+            self.take_token(uid, rate)?;
+        }
+        let max_in_flight = match self.limits.max_in_flight {
+            Some(max_in_flight) if !long_running => max_in_flight,
+            _ => return Ok(Permit { uid, in_flight: None }),
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(uid).or_default();
+        if *count >= max_in_flight {
+            return Err(exhausted(format!("UID {} already has {} requests in flight", uid, count), IN_FLIGHT_RETRY));
+        }
+        *count += 1;
+        Ok(Permit { uid, in_flight: Some(self.in_flight.clone()) })
+    }
+}
+
+/// Tower layer enforcing Limits per caller UID on the mapper service
+///
+/// Rejected requests get ResourceExhausted with a `google.rpc.RetryInfo`
+/// detail and a `retry-after` metadata entry.
+#[derive(Clone)]
+pub struct LimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl LimitLayer {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limiter: Arc::new(Limiter {
+                limits,
+                buckets: Mutex::new(HashMap::new()),
+                in_flight: Arc::new(Mutex::new(HashMap::new())),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for LimitLayer {
+    type Service = Limit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Limit { inner, limiter: self.limiter.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct Limit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Service<Request<Body>> for Limit<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let path = request.uri().path();
+        let uid = request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred)
+            .map(|cred| cred.uid());
+        // without credentials the handler rejects the request anyway
+        let permit = match uid {
+            Some(uid) if path.starts_with(LIMITED_PREFIX) => match self.limiter.admit(uid, LONG_RUNNING.contains(&path)) {
+                Ok(permit) => Some(permit),
+                Err(status) => {
+                    warn!("limit {} for UID {}: {}", path, uid, status.message());
+                    return Box::pin(async move { Ok(status.to_http()) });
+                },
+            },
+            _ => None,
+        };
+        // the ready service is used for this call, a fresh clone for the next one
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let response = inner.call(request).await;
+            drop(permit);
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: Limits) -> Limiter {
+        Limiter { limits, buckets: Mutex::new(HashMap::new()), in_flight: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    #[test]
+    fn capacity_defaults_to_rate() {
+        let limits = Limits { rate: Some(2.5), ..Default::default() };
+        assert_eq!(limits.capacity(2.5), 3.0);
+        assert_eq!(limits.capacity(0.5), 1.0);
+        let limits = Limits { rate: Some(2.5), burst: Some(0), ..Default::default() };
+        assert_eq!(limits.capacity(2.5), 1.0);
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limiter = limiter(Limits { rate: Some(100.0), burst: Some(2), ..Default::default() });
+        limiter.take_token(1000, 100.0).unwrap();
+        limiter.take_token(1000, 100.0).unwrap();
+        let status = limiter.take_token(1000, 100.0).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "1");
+        // buckets are per UID
+        limiter.take_token(1001, 100.0).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.take_token(1000, 100.0).unwrap();
+    }
+
+    #[test]
+    fn in_flight_cap_skips_long_running() {
+        let limiter = limiter(Limits { max_in_flight: Some(2), ..Default::default() });
+        let first = limiter.admit(1000, false).unwrap();
+        let _second = limiter.admit(1000, false).unwrap();
+        assert!(matches!(limiter.admit(1000, false), Err(status) if status.code() == tonic::Code::ResourceExhausted));
+        let _wait = limiter.admit(1000, true).unwrap();
+        let _other = limiter.admit(1001, false).unwrap();
+        drop(first);
+        let _third = limiter.admit(1000, false).unwrap();
+    }
+
+    #[test]
+    fn rate_must_be_positive() {
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        for value in ["0", "-1", "nan", "inf", "fast"] {
+            assert!(parse_rate(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+}
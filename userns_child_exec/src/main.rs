@@ -1,6 +1,28 @@
+#![allow(clippy::result_large_err)]
+
 mod grpc_handler;
 mod grpc_client;
+mod audit;
+mod backend;
+mod config;
+mod details;
+mod events;
+mod idmap;
+mod limit;
+mod map_cli;
+mod metrics;
+mod policy;
+mod sandbox;
+mod sandbox_cli;
+mod socket;
+mod stdio;
+mod target;
 use std::ffi::CString;
+use std::future::Future;
+use std::os::fd::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::{Parser, Subcommand, Args};
 use tokio::{process::Command, net::UnixListener};
@@ -17,7 +39,7 @@ extern crate scopeguard;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = false)]
-struct CLI {
+struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
     /// New IPC Namespace
@@ -38,9 +60,90 @@ struct CLI {
     /// New USER Namespace
     #[arg(short = 'U', long, default_value_t = false)]
     user: bool,
+    #[command(flatten)]
+    map_args: map_cli::MapArgs,
     /// Display Verbose Messages
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
+    /// TOML Configuration File of the Mapper (policy reloaded on SIGHUP)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Configuration read from --config
+    #[arg(skip)]
+    loaded: config::Config,
+    /// Path of the Mapper Socket (default: $XDG_RUNTIME_DIR/userns.sock)
+    #[arg(long, global = true, env = socket::SOCKET_ENV)]
+    socket: Option<PathBuf>,
+    /// Permission Bits of the Mapper Socket (octal, default: 600)
+    #[arg(long, value_parser = socket::parse_mode)]
+    socket_mode: Option<u32>,
+    /// Owner of the Mapper Socket (USER[:GROUP])
+    #[arg(long, value_parser = socket::parse_owner)]
+    socket_owner: Option<socket::SocketOwner>,
+    /// Append a JSON Line for Every ID Map Write to This File
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+    /// Rotate the Audit Log Once it Reaches This Size (e.g. 512K, 10M)
+    #[arg(long, default_value = "10M", value_parser = audit::parse_size)]
+    audit_max_size: u64,
+    /// Number of Rotated Audit Logs to Keep
+    #[arg(long, default_value_t = audit::DEFAULT_KEEP)]
+    audit_keep: usize,
+    /// How ID Maps are Written (default: direct)
+    #[arg(long, value_enum)]
+    backend: Option<backend::BackendKind>,
+    /// Serve Prometheus Metrics on HOST:PORT or unix:PATH
+    #[arg(long, value_parser = metrics::parse_address)]
+    metrics: Option<metrics::Address>,
+    /// Mapper Requests per Second Allowed for Each UID
+    #[arg(long, value_parser = limit::parse_rate)]
+    rate_limit: Option<f64>,
+    /// Mapper Requests a UID may Send at Once Before the Rate Limit Applies
+    #[arg(long)]
+    rate_burst: Option<u32>,
+    /// Concurrent Mapper Requests Allowed for Each UID (Except Wait, Attach and Events)
+    #[arg(long)]
+    max_in_flight: Option<usize>,
+    /// Seconds to Wait for In-flight Requests on Shutdown
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+    /// Command and Arguments to Run after `--` (default: $SHELL)
+    #[arg(last = true)]
+    argv: Vec<String>,
+}
+
+impl Cli {
+    fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .or_else(|| self.loaded.socket.clone())
+            .unwrap_or_else(socket::default_socket_path)
+    }
+
+    fn socket_mode(&self) -> u32 {
+        self.socket_mode.or(self.loaded.socket_mode).unwrap_or(0o600)
+    }
+
+    fn socket_owner(&self) -> Option<socket::SocketOwner> {
+        self.socket_owner.or(self.loaded.socket_owner)
+    }
+
+    fn limits(&self) -> limit::Limits {
+        limit::Limits {
+            rate: self.rate_limit.or(self.loaded.rate_limit),
+            burst: self.rate_burst.or(self.loaded.rate_burst),
+            max_in_flight: self.max_in_flight.or(self.loaded.max_in_flight),
+        }
+    }
+
+    fn mapper(&self) -> UsernsMapperImpl {
+        let audit = self
+            .audit_log
+            .clone()
+            .map(|path| audit::AuditLog::new(path, self.audit_max_size, self.audit_keep));
+        let backend = self.backend.or(self.loaded.backend).unwrap_or(backend::BackendKind::Direct);
+        UsernsMapperImpl::new(self.loaded.policy(), audit, backend::build(backend))
+    }
 }
 
 #[derive(Args, Debug)]
@@ -63,9 +166,12 @@ struct ChildArgs {
     /// New USER Namespace
     #[arg(short = 'U', long, default_value_t = false)]
     user: bool,
-    /// Command
+    #[command(flatten)]
+    map_args: map_cli::MapArgs,
+    /// Command (default: $SHELL)
     cmd: Option<String>,
     /// Arguments
+    #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
     args: Option<Vec<String>>
 }
 
@@ -74,51 +180,356 @@ enum Commands {
     /// Executes Child function
     /// 
     /// It shouldn't executed without server
-    Child (ChildArgs)
+    Child (ChildArgs),
+    /// Runs mapper server only, until SIGINT or SIGTERM
+    ///
+    /// Takes over the listening socket when started with systemd socket activation
+    Serve,
+    /// Prints events of the mapper server as they happen
+    Events {
+        /// Only Events Where PID is the Caller or the Target
+        #[arg(long)]
+        pid: Option<i32>,
+    },
+    /// Manages sandboxes spawned by the mapper server
+    #[command(subcommand)]
+    Sandbox (sandbox_cli::SandboxCommands),
 }
 
 const STACK_SIZE: usize = 1024 * 1024;
 
-fn child_func (command: Option<String>, args: Option<Vec<String>>, id: nix::unistd::Uid) -> isize {
-    let mut exec_args = vec![];
-    let mut client = match grpc_client::UsernsClient::connect() {
-        Ok(client) => client,
+/// Command run when none is given, the user's shell
+fn default_command() -> Vec<String> {
+    match std::env::var("SHELL") {
+        Ok(shell) if !shell.is_empty() => vec![shell],
+        _ => vec!["/bin/sh".to_string()],
+    }
+}
+
+/// Exit code reporting how a process ended, in the same way as shells
+fn exit_code(status: nix::sys::wait::WaitStatus) -> std::process::ExitCode {
+    match status {
+        nix::sys::wait::WaitStatus::Exited(_, code) => std::process::ExitCode::from(code as u8),
+        nix::sys::wait::WaitStatus::Signaled(_, signal, _) => std::process::ExitCode::from(128 + signal as u8),
+        _ => std::process::ExitCode::FAILURE,
+    }
+}
+
+/// Runs in the cloned child: waits until the parent has set up the
+/// namespaces, then executes argv
+///
+/// The parent writes a byte into the sync pipe once the ID maps are in
+/// place. If it closes the pipe without writing, setup failed.
+fn child_func (argv: &[String], sync_read: RawFd, sync_write: RawFd) -> isize {
+    // our copy of the write end would keep us from noticing the parent give up
+    let _ = nix::unistd::close(sync_write);
+    let exec_args = match argv.iter().map(|a| CString::new(a.as_str())).collect::<Result<Vec<_>, _>>() {
+        Ok(exec_args) => exec_args,
         Err(err) => {
-            println!("got error while connect: {}", err);
+            println!("invalid argument: {}", err);
             return 1
         }
     };
+    let mut buf = [0u8; 1];
+    loop {
+        match nix::unistd::read(sync_read, &mut buf) {
+            Ok(1) => break,
+            Ok(_) => return 1,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => {
+                println!("read sync pipe: {}", err);
+                return 1
+            }
+        }
+    }
+    let _ = nix::unistd::close(sync_read);
+    let Err(err) = nix::unistd::execvp(&exec_args[0], &exec_args);
+    println!("execvp {}: {}", argv[0], err);
+    // same codes as shells use for commands which can't be run
+    if err == nix::errno::Errno::ENOENT { 127 } else { 126 }
+}
+
+/// Has the mapper server write the ID maps of the cloned child
+///
+/// Runs in the parent, which is still in the original namespaces.
+/// Returns the exit code to use on failure.
+fn map_child(pid: nix::unistd::Pid, uid_map: &[idmap::IdRange], gid_map: &[idmap::IdRange], socket_path: &Path) -> Result<(), u8> {
+    let mut client = match grpc_client::UsernsClient::connect(socket_path) {
+        Ok(client) => client,
+        Err(err) => {
+            error!("got error while connect: {}", err);
+            return Err(1)
+        }
+    };
     if let Err(err) = client.ping() {
-        println!("got error while ping: {}", err);
-        return 2
+        error!("got error while ping: {}", err);
+        return Err(2)
     }
-    if let Err(err) = client.map_gid(nix::unistd::getpid(), id) {
-        println!("got error while map gid: {}", err);
-        return 3
+    if let Err(err) = client.map_gid(pid, gid_map) {
+        error!("got error while map gid: {}", err);
+        return Err(3)
     }
-    if let Err(err) = client.map_uid(nix::unistd::getpid(), id) {
-        println!("got error while map uid: {}", err);
-        return 3
+    if let Err(err) = client.map_uid(pid, uid_map) {
+        error!("got error while map uid: {}", err);
+        return Err(3)
     }
-    if let Some(cmd) = command {
-        exec_args.push(CString::new(cmd).unwrap())
+    match client.get_mapping(pid) {
+        Ok(mapping) => debug!("applied mapping: {:?}", mapping),
+        Err(err) => warn!("got error while get mapping: {}", err),
     }
-    if let Some(args_arr) = args {
-        for a in args_arr {
-            exec_args.push(CString::new(a).unwrap())
+    Ok(())
+}
+
+/// One line description of event, for printing
+fn describe_event(event: &grpc_client::userns::Event) -> String {
+    use grpc_client::userns::event::{Detail, Kind};
+    let time = event.time.as_ref().map_or_else(|| "-".to_string(), |t| t.to_string());
+    let mut line = format!("{} {}", time, event.kind().as_str_name());
+    if event.kind() != Kind::ServerShutdown {
+        line += &format!(" caller={} uid={} gid={}", event.caller_pid, event.caller_uid, event.caller_gid);
+    }
+    match &event.detail {
+        Some(Detail::Map(map)) => {
+            let ranges: Vec<idmap::IdRange> = map
+                .ranges
+                .iter()
+                .map(|r| idmap::IdRange { inside: r.id_inside_ns, outside: r.id_outside_ns, length: r.length })
+                .collect();
+            line += &format!(" {} pid={} map={:?}", map.r#type().as_str_name(), map.pid, idmap::format_map(&ranges).trim_end());
+            if map.allow_setgroups {
+                line += " setgroups=allow";
+            }
+            if !map.reason.is_empty() {
+                line += &format!(" reason={:?}", map.reason);
+            }
+        },
+        Some(Detail::Sandbox(sandbox)) => {
+            line += &format!(" {} pid={} argv={:?}", sandbox.sandbox_id, sandbox.pid, sandbox.argv);
+            if event.kind() == Kind::SandboxExited {
+                if sandbox.signal != 0 {
+                    line += &format!(" signal={}", sandbox.signal);
+                } else {
+                    line += &format!(" exit={}", sandbox.exit_code);
+                }
+            }
+        },
+        None => {},
+    }
+    line
+}
+
+/// Listening socket of the mapper server
+///
+/// Uses the socket inherited via LISTEN_FDS if there's one, otherwise binds
+/// `--socket`. The returned path is the one to remove on exit (None if inherited).
+fn open_listener(cli: &Cli, inherited: Option<std::os::unix::net::UnixListener>) -> Result<(UnixListener, Option<PathBuf>), std::process::ExitCode> {
+    if let Some(listener) = inherited {
+        info!("using socket passed with LISTEN_FDS");
+        return match UnixListener::from_std(listener) {
+            Ok(listener) => Ok((listener, None)),
+            Err(err) => {
+                error!("got error while take over LISTEN_FDS: {}", err);
+                Err(std::process::ExitCode::from(2))
+            },
         }
     }
-    if let Err(err) = nix::unistd::execvp(&exec_args[0], &exec_args) {
-        println!("execvp: {}", err);
-        return 4;
+    let path = cli.socket_path();
+    match socket::bind(&path, cli.socket_mode(), cli.socket_owner()) {
+        Ok(listener) => {
+            info!("listening on {}", path.display());
+            Ok((listener, Some(path)))
+        },
+        Err(err) => {
+            error!("got error while bind {}: {}", path.display(), err);
+            Err(std::process::ExitCode::from(2))
+        },
+    }
+}
+
+/// Bind the metrics listener, if one is configured
+async fn open_metrics(cli: &Cli) -> Result<Option<metrics::Listener>, std::process::ExitCode> {
+    let Some(address) = cli.metrics.as_ref().or(cli.loaded.metrics.as_ref()) else {
+        return Ok(None)
+    };
+    match metrics::bind(address, cli.socket_mode()).await {
+        Ok(listener) => {
+            info!("serving metrics on {:?}", address);
+            Ok(Some(listener))
+        },
+        Err(err) => {
+            error!("got error while bind metrics {:?}: {}", address, err);
+            Err(std::process::ExitCode::from(2))
+        },
+    }
+}
+
+fn remove_socket(path: Option<&Path>) {
+    if let Some(path) = path {
+        info!("clean up {}", path.display());
+        if let Err(err) = std::fs::remove_file(path) {
+            error!("got error while delete {}: {}", path.display(), err)
+        }
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("got SIGINT, shutting down"),
+        _ = sigterm.recv() => info!("got SIGTERM, shutting down"),
+    }
+}
+
+/// Reload the policy from the configuration file on every SIGHUP
+///
+/// A file which fails to load is reported and the current policy is kept.
+async fn reload_on_sighup(path: PathBuf, policy: policy::SharedPolicy) {
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+    while sighup.recv().await.is_some() {
+        match config::Config::load(&path) {
+            Ok(config) => {
+                info!("got SIGHUP, reloaded policy from {}", path.display());
+                policy.set(config.policy());
+            },
+            Err(err) => error!("got error while reload config: {}", err),
+        }
+    }
+}
+
+/// Serve mapper, health and reflection services on listener until shutdown,
+/// and metrics on metrics_listener if given
+///
+/// Mapper requests are subject to limits per caller UID.
+///
+/// Once shutdown resolves, health turns NOT_SERVING, new connections are
+/// refused and in-flight requests get up to timeout to finish. Sandboxes
+/// still running after that are killed.
+async fn serve(mapper: UsernsMapperImpl, listener: UnixListener, metrics_listener: Option<metrics::Listener>, limits: limit::Limits, shutdown: impl Future<Output = ()>, timeout: Duration) -> Result<(), String> {
+    let metrics_task = metrics_listener.map(|l| tokio::spawn(metrics::serve(l, mapper.metrics())));
+    defer! {
+        if let Some(task) = &metrics_task {
+            task.abort();
+        }
+    }
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter.set_serving::<UsernsMapperServer<UsernsMapperImpl>>().await;
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(grpc_handler::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .map_err(|err| format!("reflection: {}", err))?;
+    let shutdown_handle = mapper.shutdown_handle();
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    if !limits.is_unlimited() {
+        info!("limiting mapper requests per UID: {:?}", limits);
+    }
+    let server = tonic::transport::Server::builder()
+        .layer(limit::LimitLayer::new(limits))
+        .add_service(health_service)
+        .add_service(reflection_service)
+        .add_service(UsernsMapperServer::new(mapper))
+        .serve_with_incoming_shutdown(UnixListenerStream::new(listener), async {
+            let _ = stop_rx.await;
+        });
+    tokio::pin!(server);
+    tokio::select! {
+        res = &mut server => return res.map_err(|err| err.to_string()),
+        _ = shutdown => {},
+    }
+    health_reporter.set_service_status("", tonic_health::ServingStatus::NotServing).await;
+    health_reporter.set_not_serving::<UsernsMapperServer<UsernsMapperImpl>>().await;
+    shutdown_handle.trigger();
+    let _ = stop_tx.send(());
+    let res = match tokio::time::timeout(timeout, server).await {
+        Ok(res) => {
+            info!("all requests have been drained");
+            res.map_err(|err| err.to_string())
+        },
+        Err(_) => {
+            warn!("requests still in flight after {:?}, closing connections", timeout);
+            Ok(())
+        },
+    };
+    shutdown_handle.stop_sandboxes().await;
+    res
+}
+
+/// Mapper server running in the background, see start_server()
+struct RunningServer {
+    task: tokio::task::JoinHandle<Result<(), String>>,
+    /// Socket to remove once the server is done (None if inherited)
+    socket_path: Option<PathBuf>,
+}
+
+impl RunningServer {
+    /// Wait until the server has shut down and clean up its socket
+    async fn join(self) -> Result<(), String> {
+        let res = match self.task.await {
+            Ok(res) => res,
+            Err(err) => Err(format!("server task failed: {}", err)),
+        };
+        remove_socket(self.socket_path.as_deref());
+        res
+    }
+}
+
+/// Open the sockets and start the mapper server, which reloads its policy
+/// on SIGHUP and shuts down gracefully once shutdown resolves
+///
+/// Shared by `serve` and the default mode, which stops it after the child.
+async fn start_server(cli: &Cli, inherited: Option<std::os::unix::net::UnixListener>, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<RunningServer, std::process::ExitCode> {
+    let (grpc_socket, socket_path) = open_listener(cli, inherited)?;
+    let metrics_listener = match open_metrics(cli).await {
+        Ok(listener) => listener,
+        Err(code) => {
+            remove_socket(socket_path.as_deref());
+            return Err(code)
+        },
+    };
+    debug!("spawn grpc server");
+    let timeout = Duration::from_secs(cli.shutdown_timeout);
+    let mapper = cli.mapper();
+    if let Some(path) = cli.config.clone() {
+        tokio::spawn(reload_on_sighup(path, mapper.policy_handle()));
+    }
+    let task = tokio::spawn(serve(mapper, grpc_socket, metrics_listener, cli.limits(), shutdown, timeout));
+    Ok(RunningServer { task, socket_path })
+}
+
+/// Wait for the child of the default command
+///
+/// SIGTERM is forwarded to it, SIGINT is left to the child (e.g. a shell).
+async fn wait_child(child: &mut tokio::process::Child) -> std::io::Result<std::process::ExitStatus> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            status = child.wait() => return status,
+            _ = sigterm.recv() => {
+                info!("got SIGTERM, forwarding to child");
+                if let Some(pid) = child.id() {
+                    let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), nix::sys::signal::Signal::SIGTERM);
+                }
+            },
+            _ = sigint.recv() => debug!("got SIGINT, leaving it to child"),
+        }
     }
-    0
 }
 
 fn main() -> std::process::ExitCode {
-    let _cli = CLI::parse();
+    let mut _cli = Cli::parse();
+    if let Some(path) = &_cli.config {
+        match config::Config::load(path) {
+            Ok(config) => _cli.loaded = config,
+            Err(err) => {
+                eprintln!("got error while load config: {}", err);
+                return std::process::ExitCode::from(2)
+            }
+        }
+    }
     match &_cli.command {
-        Some(Commands::Child (ChildArgs { ipc, mount, network, pid, uts, user, cmd, args })) => {
+        Some(Commands::Child (ChildArgs { ipc, mount, network, pid, uts, user, map_args, cmd, args })) => {
             // Set Verbose Mode
             std::env::set_var("RUST_LOG", "DEBUG");
             env_logger::init();
@@ -141,59 +552,149 @@ fn main() -> std::process::ExitCode {
             if *user {
                 clone_flags |= nix::sched::CloneFlags::CLONE_NEWUSER;
             }
-            let id = nix::unistd::geteuid();
+            let argv = match cmd {
+                Some(cmd) => std::iter::once(cmd.clone()).chain(args.iter().flatten().cloned()).collect(),
+                None => default_command(),
+            };
+            let maps = map_args.ranges(policy::IdKind::Uid).and_then(|uid_map| Ok((uid_map, map_args.ranges(policy::IdKind::Gid)?)));
+            let (uid_map, gid_map) = match maps {
+                Ok(maps) => maps,
+                Err(err) => {
+                    error!("invalid mapping: {}", err);
+                    return std::process::ExitCode::from(2)
+                }
+            };
+            let (sync_read, sync_write) = match nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC) {
+                Ok(fds) => fds,
+                Err(err) => {
+                    error!("got error while pipe: {}", err);
+                    return std::process::ExitCode::from(1)
+                }
+            };
             let mut child_stack = vec![0; STACK_SIZE];
             let cb_func = Box::new(|| {
-                child_func(cmd.clone(), args.clone(), id.clone())
+                child_func(&argv, sync_read, sync_write)
             });
-            let pid = match nix::sched::clone(cb_func, &mut child_stack, clone_flags, Some(nix::sys::signal::SIGCHLD as i32)) {
+            let pid = nix::sched::clone(cb_func, &mut child_stack, clone_flags, Some(nix::sys::signal::SIGCHLD as i32));
+            let _ = nix::unistd::close(sync_read);
+            let pid = match pid {
                 Ok(pid) => pid,
                 Err(err) => {
                     error!("got error while clone: {}", err);
+                    let _ = nix::unistd::close(sync_write);
                     return std::process::ExitCode::from(1)
                 }
             };
-            if let Err(err) = nix::sys::wait::waitpid(pid, None) {
-                error!("waitpid: {}", err);
+            let res = if *user { map_child(pid, &uid_map, &gid_map, &_cli.socket_path()) } else { Ok(()) };
+            // releases the child, or makes it give up if nothing was written
+            if res.is_ok() {
+                if let Err(err) = nix::unistd::write(sync_write, &[0]) {
+                    error!("got error while release child: {}", err);
+                }
+            }
+            let _ = nix::unistd::close(sync_write);
+            let status = nix::sys::wait::waitpid(pid, None);
+            if let Err(code) = res {
+                return std::process::ExitCode::from(code)
+            }
+            match status {
+                Ok(status) => exit_code(status),
+                Err(err) => {
+                    error!("waitpid: {}", err);
+                    std::process::ExitCode::from(2)
+                }
+            }
+        },
+        Some(Commands::Sandbox (command)) => {
+            if _cli.verbose {
+                std::env::set_var("RUST_LOG", "DEBUG");
+            }
+            env_logger::init();
+            sandbox_cli::run(command, &_cli.socket_path())
+        },
+        Some(Commands::Events { pid }) => {
+            if _cli.verbose {
+                std::env::set_var("RUST_LOG", "DEBUG");
+            }
+            env_logger::init();
+            let mut client = match grpc_client::UsernsClient::connect(&_cli.socket_path()) {
+                Ok(client) => client,
+                Err(err) => {
+                    println!("got error while connect: {}", err);
+                    return std::process::ExitCode::from(1)
+                }
+            };
+            let res = client.events(pid.map(nix::unistd::Pid::from_raw), |event| {
+                println!("{}", describe_event(&event))
+            });
+            if let Err(err) = res {
+                println!("got error while receive events: {}", err);
                 return std::process::ExitCode::from(2)
             }
-            return std::process::ExitCode::SUCCESS;
+            std::process::ExitCode::SUCCESS
         },
-        None => {
-            return tokio::runtime::Builder::new_multi_thread().
+        Some(Commands::Serve) => {
+            if _cli.verbose {
+                std::env::set_var("RUST_LOG", "DEBUG");
+            }
+            env_logger::init();
+            let inherited = match socket::take_listen_fds() {
+                Ok(inherited) => inherited,
+                Err(err) => {
+                    error!("got error while take over LISTEN_FDS: {}", err);
+                    return std::process::ExitCode::from(2)
+                },
+            };
+            tokio::runtime::Builder::new_multi_thread().
                 enable_all().
                 build().
                 unwrap().
                 block_on(async {
-                    if _cli.verbose {
-                        std::env::set_var("RUST_LOG", "DEBUG");
-                    }
-                    defer! {
-                        info!("clean up /tmp/userns.sock");
-                        if let Err(err) = std::fs::remove_file("/tmp/userns.sock") {
-                            error!("got error while delete /tmp/userns.sock: {}", err)
-                        }
+                    let server = match start_server(&_cli, inherited, shutdown_signal()).await {
+                        Ok(server) => server,
+                        Err(code) => return code,
+                    };
+                    if let Err(err) = server.join().await {
+                        error!("got error while serve: {}", err);
+                        return std::process::ExitCode::from(2)
                     }
-                    env_logger::init();
-                    debug!("spawn grpc server");
-                    let grpc_server = tonic::transport::Server::builder().add_service(UsernsMapperServer::new(UsernsMapperImpl::default()));
-                    let grpc_socket = match UnixListener::bind("/tmp/userns.sock") {
-                        Ok(sock) => sock,
-                        Err(err) => {
-                            error!("got error while bind /tmp/userns.sock: {}", err);
-                            return std::process::ExitCode::from(2);
-                        },
+                    std::process::ExitCode::SUCCESS
+                })
+        },
+        None => {
+            if _cli.verbose {
+                std::env::set_var("RUST_LOG", "DEBUG");
+            }
+            env_logger::init();
+            let inherited = match socket::take_listen_fds() {
+                Ok(inherited) => inherited,
+                Err(err) => {
+                    error!("got error while take over LISTEN_FDS: {}", err);
+                    return std::process::ExitCode::from(2)
+                },
+            };
+            tokio::runtime::Builder::new_multi_thread().
+                enable_all().
+                build().
+                unwrap().
+                block_on(async {
+                    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+                    let server = match start_server(&_cli, inherited, async {
+                        let _ = stop_rx.await;
+                    }).await {
+                        Ok(server) => server,
+                        Err(code) => return code,
                     };
-                    let _grpc_task = tokio::task::spawn(async move {
-                        if let Err(err) = grpc_server.serve_with_incoming(UnixListenerStream::new(grpc_socket)).await {
-                            panic!("got error while serve: {}", err)
+                    // the server is shut down gracefully once the child is gone
+                    let stop_server = || async {
+                        let _ = stop_tx.send(());
+                        if let Err(err) = server.join().await {
+                            error!("got error while serve: {}", err);
                         }
-                    });
-                    defer! {
-                        _grpc_task.abort()
-                    }
+                    };
                     let mut command = Command::new("/proc/self/exe");
                     command.arg("child");
+                    command.arg("--socket").arg(_cli.socket_path());
                     if _cli.ipc {
                         command.arg("--ipc");
                     };
@@ -212,24 +713,32 @@ fn main() -> std::process::ExitCode {
                     if _cli.user {
                         command.arg("--user");
                     };
-                    command.arg("zsh");
+                    command.args(_cli.map_args.to_args());
+                    command.arg("--");
+                    if _cli.argv.is_empty() {
+                        command.args(default_command());
+                    } else {
+                        command.args(&_cli.argv);
+                    }
                     let mut child = match command.spawn() {
                         Ok(child) => child,
                         Err(err) => {
                             error!("err: {}", err);
+                            stop_server().await;
                             return std::process::ExitCode::from(1)
                         }
                     };
-                    match child.wait().await {
-                        Ok(exitcode) => {
-                            if exitcode.success() {
-                                return std::process::ExitCode::SUCCESS
-                            }
-                            return std::process::ExitCode::from(1)
+                    let status = wait_child(&mut child).await;
+                    stop_server().await;
+                    match status {
+                        Ok(exitcode) => match (exitcode.code(), exitcode.signal()) {
+                            (Some(code), _) => std::process::ExitCode::from(code as u8),
+                            (None, Some(signal)) => std::process::ExitCode::from(128 + signal as u8),
+                            _ => std::process::ExitCode::FAILURE,
                         },
                         Err(err) => {
                             error!("wait(): {}", err);
-                            return std::process::ExitCode::from(1)
+                            std::process::ExitCode::from(1)
                         }
                     }
                 })
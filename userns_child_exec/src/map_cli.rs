@@ -0,0 +1,85 @@
+use clap::Args;
+
+use crate::idmap::{self, IdRange};
+use crate::policy::{self, IdKind};
+
+/// Options selecting the UID / GID maps of a new USER namespace
+///
+/// Without any of them the own UID / GID is mapped to root. They all
+/// need a new USER namespace (`--user`).
+#[derive(Args, Debug, Clone, Default)]
+pub struct MapArgs {
+    /// UID Map Range inside:outside:length (repeatable)
+    #[arg(long = "uid-map", value_name = "RANGE", value_parser = idmap::parse_range, requires = "user")]
+    pub uid_map: Vec<IdRange>,
+    /// GID Map Range inside:outside:length (repeatable)
+    #[arg(long = "gid-map", value_name = "RANGE", value_parser = idmap::parse_range, requires = "user")]
+    pub gid_map: Vec<IdRange>,
+    /// Map Own UID / GID to Root
+    #[arg(long, default_value_t = false, requires = "user", conflicts_with = "map_self")]
+    pub map_root: bool,
+    /// Map Own UID / GID to Itself
+    #[arg(long, default_value_t = false, requires = "user")]
+    pub map_self: bool,
+    /// Map Remaining IDs to the Own Ranges in /etc/subuid and /etc/subgid
+    #[arg(long, default_value_t = false, requires = "user")]
+    pub subids: bool,
+}
+
+/// Subordinate ranges of the current user, for the given map
+fn own_subids(kind: IdKind) -> Result<Vec<policy::SubIdRange>, String> {
+    // both files are keyed by user (name or UID), not by group
+    let path = match kind {
+        IdKind::Uid => "/etc/subuid",
+        IdKind::Gid => "/etc/subgid",
+    };
+    let uid = nix::unistd::geteuid();
+    let user = nix::unistd::User::from_uid(uid).ok().flatten();
+    let content = std::fs::read_to_string(path).map_err(|err| format!("read {}: {}", path, err))?;
+    let ranges = policy::parse_subid(&content, user.as_ref().map(|u| u.name.as_str()), uid.as_raw());
+    if ranges.is_empty() {
+        return Err(format!("no subordinate IDs for UID {} in {}", uid, path));
+    }
+    Ok(ranges)
+}
+
+impl MapArgs {
+    /// Ranges of the given map, for the current effective UID / GID
+    pub fn ranges(&self, kind: IdKind) -> Result<Vec<IdRange>, String> {
+        let (mut ranges, own) = match kind {
+            IdKind::Uid => (self.uid_map.clone(), nix::unistd::geteuid().as_raw()),
+            IdKind::Gid => (self.gid_map.clone(), nix::unistd::getegid().as_raw()),
+        };
+        if self.map_self {
+            ranges.push(IdRange { inside: own, outside: own, length: 1 });
+        } else if self.map_root || ranges.is_empty() {
+            ranges.push(IdRange { inside: 0, outside: own, length: 1 });
+        }
+        if self.subids {
+            idmap::fill_unmapped(&mut ranges, &own_subids(kind)?);
+        }
+        idmap::validate(&ranges).map_err(|err| format!("{:?} map: {}", kind, err))?;
+        Ok(ranges)
+    }
+
+    /// Same options as command line arguments, for passing them on
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for (option, ranges) in [("--uid-map", &self.uid_map), ("--gid-map", &self.gid_map)] {
+            for r in ranges {
+                args.push(option.to_string());
+                args.push(format!("{}:{}:{}", r.inside, r.outside, r.length));
+            }
+        }
+        if self.map_root {
+            args.push("--map-root".to_string());
+        }
+        if self.map_self {
+            args.push("--map-self".to_string());
+        }
+        if self.subids {
+            args.push("--subids".to_string());
+        }
+        args
+    }
+}
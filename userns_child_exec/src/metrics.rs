@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::policy::IdKind;
+
+/// Upper bounds of the Map latency buckets, in seconds
+const DURATION_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Where metrics are served, `HOST:PORT` or `unix:PATH`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for Address {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_address(&value)
+    }
+}
+
+pub fn parse_address(value: &str) -> Result<Address, String> {
+    if let Some(path) = value.strip_prefix("unix:") {
+        return Ok(Address::Unix(PathBuf::from(path)));
+    }
+    value
+        .parse()
+        .map(Address::Tcp)
+        .map_err(|err| format!("invalid address {:?} (HOST:PORT or unix:PATH): {}", value, err))
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is +Inf
+    counts: [u64; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = DURATION_BUCKETS.iter().position(|&le| value <= le).unwrap_or(DURATION_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Counters of the mapper, rendered in the Prometheus text format
+pub struct Metrics {
+    /// Map requests by (type, result)
+    map_requests: Mutex<BTreeMap<(&'static str, String), u64>>,
+    map_duration: Mutex<Histogram>,
+    sandboxes_active: AtomicI64,
+    /// Requests denied by policy, by RPC
+    denials: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            map_requests: Mutex::new(BTreeMap::new()),
+            map_duration: Mutex::new(Histogram::default()),
+            sandboxes_active: AtomicI64::new(0),
+            denials: Mutex::new(BTreeMap::from([("get_mapping", 0), ("map", 0), ("spawn", 0)])),
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a handled Map request, with its result code and duration
+    ///
+    /// kind is None if the request had an invalid map type.
+    pub fn record_map(&self, kind: Option<IdKind>, code: tonic::Code, elapsed: Duration) {
+        let kind = match kind {
+            Some(IdKind::Uid) => "uid",
+            Some(IdKind::Gid) => "gid",
+            None => "unknown",
+        };
+        let result = if code == tonic::Code::Ok { "OK".to_string() } else { format!("{:?}", code) };
+        *self.map_requests.lock().unwrap().entry((kind, result)).or_default() += 1;
+        self.map_duration.lock().unwrap().observe(elapsed.as_secs_f64());
+    }
+
+    /// Count a request denied by policy
+    pub fn denied(&self, request: &'static str) {
+        *self.denials.lock().unwrap().entry(request).or_default() += 1;
+    }
+
+    pub fn sandbox_started(&self) {
+        self.sandboxes_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sandbox_exited(&self) {
+        self.sandboxes_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP userns_map_requests_total Map requests by map type and result.\n");
+        out.push_str("# TYPE userns_map_requests_total counter\n");
+        for ((kind, result), count) in self.map_requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "userns_map_requests_total{{type=\"{}\",result=\"{}\"}} {}", kind, result, count);
+        }
+        out.push_str("# HELP userns_map_duration_seconds Time taken to handle Map requests.\n");
+        out.push_str("# TYPE userns_map_duration_seconds histogram\n");
+        {
+            let histogram = self.map_duration.lock().unwrap();
+            let mut cumulative = 0;
+            for (le, count) in DURATION_BUCKETS.iter().zip(histogram.counts.iter()) {
+                cumulative += count;
+                let _ = writeln!(out, "userns_map_duration_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+            }
+            let _ = writeln!(out, "userns_map_duration_seconds_bucket{{le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "userns_map_duration_seconds_sum {}", histogram.sum);
+            let _ = writeln!(out, "userns_map_duration_seconds_count {}", histogram.count);
+        }
+        out.push_str("# HELP userns_sandboxes_active Sandboxes currently running.\n");
+        out.push_str("# TYPE userns_sandboxes_active gauge\n");
+        let _ = writeln!(out, "userns_sandboxes_active {}", self.sandboxes_active.load(Ordering::Relaxed));
+        out.push_str("# HELP userns_policy_denials_total Requests denied by policy, by RPC.\n");
+        out.push_str("# TYPE userns_policy_denials_total counter\n");
+        for (request, count) in self.denials.lock().unwrap().iter() {
+            let _ = writeln!(out, "userns_policy_denials_total{{request=\"{}\"}} {}", request, count);
+        }
+        out
+    }
+}
+
+/// Bound metrics listener
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Listener and the socket path, removed when serving stops
+    Unix(UnixListener, PathBuf),
+}
+
+/// Bind address; a Unix socket gets the given mode, like the mapper socket
+pub async fn bind(address: &Address, mode: u32) -> std::io::Result<Listener> {
+    match address {
+        Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        Address::Unix(path) => Ok(Listener::Unix(crate::socket::bind(path, mode, None)?, path.clone())),
+    }
+}
+
+fn respond(request: &Request<Body>, metrics: &Metrics) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    } else if request.method() != Method::GET && request.method() != Method::HEAD {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else {
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+        *response.body_mut() = Body::from(metrics.render());
+    }
+    response
+}
+
+fn handle<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, metrics: Arc<Metrics>) {
+    tokio::spawn(async move {
+        let service = service_fn(move |request| {
+            let metrics = metrics.clone();
+            async move { Ok::<_, Infallible>(respond(&request, &metrics)) }
+        });
+        if let Err(err) = hyper::server::conn::Http::new().http1_only(true).serve_connection(stream, service).await {
+            debug!("metrics connection: {}", err);
+        }
+    });
+}
+
+/// Serve `GET /metrics` over HTTP/1 until the task is dropped
+pub async fn serve(listener: Listener, metrics: Arc<Metrics>) {
+    let path = match &listener {
+        Listener::Unix(_, path) => Some(path.clone()),
+        Listener::Tcp(_) => None,
+    };
+    defer! {
+        if let Some(path) = &path {
+            info!("clean up {}", path.display());
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    loop {
+        let res = match &listener {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| handle(stream, metrics.clone())),
+            Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| handle(stream, metrics.clone())),
+        };
+        if let Err(err) = res {
+            warn!("accept metrics connection: {}", err);
+            // e.g. out of file descriptors, don't spin
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::idmap::IdRange;
+use crate::target::Target;
+use tonic::transport::server::UdsConnectInfo;
+use tonic::{Request, Status};
+
+/// Maximum depth walked up the process tree before giving up
+const MAX_TREE_DEPTH: usize = 4096;
+
+/// Credentials of the process on the other side of the Unix socket
+///
+/// Taken from `SO_PEERCRED`, so they can't be forged by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Caller {
+    /// Read the peer credentials attached to request by tonic
+    pub fn from_request<T>(request: &Request<T>) -> Result<Self, Status> {
+        let cred = request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred)
+            .ok_or_else(|| Status::new(tonic::Code::Unauthenticated, "peer credentials are not available"))?;
+        let pid = cred
+            .pid()
+            .ok_or_else(|| Status::new(tonic::Code::Unauthenticated, "peer PID is not available"))?;
+        Ok(Self { pid, uid: cred.uid(), gid: cred.gid() })
+    }
+}
+
+/// Which of the ID maps a request targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdKind {
+    Uid,
+    Gid,
+}
+
+/// Range of subordinate IDs from `/etc/subuid` or `/etc/subgid`
+///
+/// In the configuration file it's written as `"start:count"`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct SubIdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+impl SubIdRange {
+    /// Whether `[start, start + length)` lies completely within this range
+    pub fn contains(&self, start: u32, length: u32) -> bool {
+        let end = start as u64 + length as u64;
+        start >= self.start && end <= self.start as u64 + self.count as u64
+    }
+}
+
+impl TryFrom<String> for SubIdRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (start, count) = value.split_once(':').ok_or_else(|| format!("expected start:count, got {:?}", value))?;
+        let parse = |s: &str| s.parse::<u32>().map_err(|err| format!("invalid ID {:?}: {}", s, err));
+        Ok(Self { start: parse(start)?, count: parse(count)? })
+    }
+}
+
+/// Parse `/etc/subuid` format (`name_or_id:start:count`) for the given owner
+///
+/// Lines which don't belong to the owner or which are malformed are skipped.
+pub fn parse_subid(content: &str, name: Option<&str>, id: u32) -> Vec<SubIdRange> {
+    let id = id.to_string();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split(':');
+            let owner = fields.next()?;
+            if owner != id && Some(owner) != name {
+                return None;
+            }
+            let start = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            Some(SubIdRange { start, count })
+        })
+        .collect()
+}
+
+/// Parent PID in the content of `/proc/PID/stat`
+pub fn parse_parent_pid(stat: &str) -> Option<i32> {
+    // comm may contain spaces and parens, so fields start after the last ')'
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Parent PID of a process, from `/proc/PID/stat`
+fn parent_pid(pid: i32) -> Option<i32> {
+    parse_parent_pid(&std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+/// Whether `target` is `ancestor` itself or one of its descendants
+pub fn is_in_process_tree(ancestor: i32, target: i32) -> bool {
+    let mut pid = target;
+    for _ in 0..MAX_TREE_DEPTH {
+        if pid == ancestor {
+            return true;
+        }
+        match parent_pid(pid) {
+            Some(ppid) if ppid > 0 => pid = ppid,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Limits for a user or group, from the configuration file
+///
+/// Unset fields fall back to the next matching rule: the user's own,
+/// then those of its groups (in name order), then the defaults.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Allow the ranges in `/etc/subuid` and `/etc/subgid` (default: true)
+    pub subids: Option<bool>,
+    /// Further outside UID ranges that may be mapped
+    pub uid_ranges: Option<Vec<SubIdRange>>,
+    /// Further outside GID ranges that may be mapped
+    pub gid_ranges: Option<Vec<SubIdRange>>,
+    /// Maximum length of a single range
+    pub max_length: Option<u32>,
+    /// Whether `setgroups allow` may be requested at all (default: true);
+    /// it still needs a GID map beyond the own GID, see may_allow_setgroups
+    pub allow_setgroups: Option<bool>,
+    /// Maximum number of running sandboxes
+    pub max_sandboxes: Option<usize>,
+}
+
+impl Rule {
+    /// Rule with the unset fields taken from other
+    fn or(self, other: &Rule) -> Rule {
+        Rule {
+            subids: self.subids.or(other.subids),
+            uid_ranges: self.uid_ranges.or_else(|| other.uid_ranges.clone()),
+            gid_ranges: self.gid_ranges.or_else(|| other.gid_ranges.clone()),
+            max_length: self.max_length.or(other.max_length),
+            allow_setgroups: self.allow_setgroups.or(other.allow_setgroups),
+            max_sandboxes: self.max_sandboxes.or(other.max_sandboxes),
+        }
+    }
+}
+
+/// Decides which mappings a caller may request, in the same way as
+/// newuidmap(1) / newgidmap(1)
+///
+/// A caller may always map its own UID (or GID) with length 1, and
+/// additionally any range assigned to it in `/etc/subuid` (or `/etc/subgid`)
+/// or by its rules. Root callers could write the maps themselves, so
+/// they're not restricted.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub subuid_path: PathBuf,
+    pub subgid_path: PathBuf,
+    pub defaults: Rule,
+    /// Rules keyed by user name or UID
+    pub users: BTreeMap<String, Rule>,
+    /// Rules keyed by group name or GID
+    pub groups: BTreeMap<String, Rule>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            subuid_path: PathBuf::from("/etc/subuid"),
+            subgid_path: PathBuf::from("/etc/subgid"),
+            defaults: Rule::default(),
+            users: BTreeMap::new(),
+            groups: BTreeMap::new(),
+        }
+    }
+}
+
+/// Policy in use by the server, replaced when the configuration is reloaded
+#[derive(Clone, Default)]
+pub struct SharedPolicy(Arc<RwLock<Arc<Policy>>>);
+
+impl SharedPolicy {
+    pub fn new(policy: Policy) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(policy))))
+    }
+
+    /// Current policy; requests keep using it even if it's replaced meanwhile
+    pub fn get(&self) -> Arc<Policy> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, policy: Policy) {
+        *self.0.write().unwrap() = Arc::new(policy);
+    }
+}
+
+impl Policy {
+    /// Effective rule of caller, merged from its user, group and default rules
+    pub fn rule(&self, caller: &Caller) -> Rule {
+        let uid = nix::unistd::Uid::from_raw(caller.uid);
+        let gid = nix::unistd::Gid::from_raw(caller.gid);
+        let user = nix::unistd::User::from_uid(uid).ok().flatten();
+        let mut rule = Rule::default();
+        let own = self
+            .users
+            .get(&caller.uid.to_string())
+            .or_else(|| user.as_ref().and_then(|u| self.users.get(&u.name)));
+        if let Some(own) = own {
+            rule = rule.or(own);
+        }
+        if !self.groups.is_empty() {
+            let gids = user
+                .as_ref()
+                .and_then(|u| CString::new(u.name.as_str()).ok())
+                .and_then(|name| nix::unistd::getgrouplist(&name, gid).ok())
+                .unwrap_or_else(|| vec![gid]);
+            let mut keys: Vec<String> = gids.iter().map(|g| g.to_string()).collect();
+            keys.extend(gids.iter().filter_map(|&g| nix::unistd::Group::from_gid(g).ok().flatten()).map(|g| g.name));
+            for (key, group) in &self.groups {
+                if keys.contains(key) {
+                    rule = rule.or(group);
+                }
+            }
+        }
+        rule.or(&self.defaults)
+    }
+
+    /// Subordinate ranges of the caller for the given map
+    pub fn subid_ranges(&self, caller: &Caller, kind: IdKind) -> Vec<SubIdRange> {
+        // both files are keyed by user (name or UID), not by group
+        let path: &Path = match kind {
+            IdKind::Uid => &self.subuid_path,
+            IdKind::Gid => &self.subgid_path,
+        };
+        let user = nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(caller.uid)).ok().flatten();
+        match std::fs::read_to_string(path) {
+            Ok(content) => parse_subid(&content, user.as_ref().map(|u| u.name.as_str()), caller.uid),
+            Err(err) => {
+                debug!("read {}: {}", path.display(), err);
+                vec![]
+            }
+        }
+    }
+
+    /// Outside ranges caller may map besides its own ID
+    fn allowed_ranges(&self, caller: &Caller, kind: IdKind, rule: &Rule) -> Vec<SubIdRange> {
+        let mut ranges = if rule.subids.unwrap_or(true) { self.subid_ranges(caller, kind) } else { vec![] };
+        let extra = match kind {
+            IdKind::Uid => &rule.uid_ranges,
+            IdKind::Gid => &rule.gid_ranges,
+        };
+        ranges.extend(extra.iter().flatten());
+        ranges
+    }
+
+    /// Whether caller may keep setgroups(2) allowed in a namespace getting gid_map
+    ///
+    /// Same condition as newgidmap(1) uses: besides the caller's own GID,
+    /// the map has to include ranges, all of them assigned to the caller.
+    /// A map of just the own GID always gets `deny`.
+    pub fn may_allow_setgroups(&self, caller: &Caller, gid_map: &[IdRange]) -> bool {
+        if caller.uid == 0 {
+            return true;
+        }
+        let others: Vec<&IdRange> = gid_map
+            .iter()
+            .filter(|r| !(r.outside == caller.gid && r.length == 1))
+            .collect();
+        if others.is_empty() {
+            return false;
+        }
+        let rule = self.rule(caller);
+        if rule.allow_setgroups == Some(false) {
+            return false;
+        }
+        let allowed = self.allowed_ranges(caller, IdKind::Gid, &rule);
+        others.iter().all(|r| allowed.iter().any(|a| a.contains(r.outside, r.length)))
+    }
+
+    /// Maximum number of running sandboxes of caller, None if unlimited
+    pub fn max_sandboxes(&self, caller: &Caller) -> Option<usize> {
+        self.rule(caller).max_sandboxes
+    }
+
+    /// Check that target is the caller or one of its descendants, and
+    /// unless caller is root, that it belongs to caller: either runs with
+    /// its effective UID or is in a user namespace caller owns
+    ///
+    /// caller.pid is only known from connecting, so it may have been
+    /// reused; ownership is what keeps other users' processes out.
+    pub fn check_target(&self, caller: &Caller, target: &Target) -> Result<(), Status> {
+        let pid = target.pid() as i32;
+        let in_tree = pid == caller.pid || target.parent_pid().is_ok_and(|ppid| is_in_process_tree(caller.pid, ppid));
+        if !in_tree {
+            return Err(Status::new(
+                tonic::Code::PermissionDenied,
+                format!("process {} is not in the process tree of caller {}", pid, caller.pid),
+            ));
+        }
+        if caller.uid == 0 {
+            return Ok(());
+        }
+        if target.euid().is_ok_and(|euid| euid == caller.uid) || target.user_ns_owner().is_ok_and(|owner| owner == caller.uid) {
+            return Ok(());
+        }
+        Err(Status::new(
+            tonic::Code::PermissionDenied,
+            format!("process {} does not belong to UID {}", pid, caller.uid),
+        ))
+    }
+
+    /// Check that caller may map `outside`..`outside + length` into the given map
+    pub fn check_range(&self, caller: &Caller, kind: IdKind, outside: u32, length: u32) -> Result<(), Status> {
+        if caller.uid == 0 {
+            return Ok(());
+        }
+        let own_id = match kind {
+            IdKind::Uid => caller.uid,
+            IdKind::Gid => caller.gid,
+        };
+        if outside == own_id && length == 1 {
+            return Ok(());
+        }
+        let rule = self.rule(caller);
+        if let Some(max_length) = rule.max_length {
+            if length > max_length {
+                return Err(Status::new(
+                    tonic::Code::PermissionDenied,
+                    format!("{:?} range {}+{} is longer than {} allowed for UID {}", kind, outside, length, max_length, caller.uid),
+                ));
+            }
+        }
+        if self.allowed_ranges(caller, kind, &rule).iter().any(|r| r.contains(outside, length)) {
+            return Ok(());
+        }
+        Err(Status::new(
+            tonic::Code::PermissionDenied,
+            format!("{:?} range {}+{} is not allowed for UID {}", kind, outside, length, caller.uid),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IDs which have no user or group on the test host
+    const UID: u32 = 3999999;
+    const GID: u32 = 3999998;
+
+    #[test]
+    fn parse_subid_matches_name_or_id() {
+        let content = "alice:100000:65536\n1000:200000:10\n  bob:300000:1\nalice:oops:1\nalice:400000\n";
+        let ranges = parse_subid(content, Some("alice"), 1000);
+        assert_eq!(ranges, vec![SubIdRange { start: 100000, count: 65536 }, SubIdRange { start: 200000, count: 10 }]);
+        assert_eq!(parse_subid(content, None, 1001), vec![]);
+        assert_eq!(parse_subid(content, Some("bob"), 1001), vec![SubIdRange { start: 300000, count: 1 }]);
+    }
+
+    #[test]
+    fn subid_range_contains() {
+        let range = SubIdRange::try_from("100000:10".to_string()).unwrap();
+        assert!(range.contains(100000, 10));
+        assert!(range.contains(100009, 1));
+        assert!(!range.contains(99999, 1));
+        assert!(!range.contains(100005, 6));
+        assert!(!SubIdRange { start: u32::MAX, count: 1 }.contains(u32::MAX, u32::MAX));
+        assert!(SubIdRange::try_from("100000".to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_parent_pid_skips_comm() {
+        assert_eq!(parse_parent_pid("42 (sh) S 7 42 42 0"), Some(7));
+        assert_eq!(parse_parent_pid("42 (a) b (c) S 9 42 42 0"), Some(9));
+        assert_eq!(parse_parent_pid("42 (sh"), None);
+    }
+
+    #[test]
+    fn rule_falls_back_to_groups_then_defaults() {
+        let user = Rule { max_length: Some(5), ..Default::default() };
+        let group = Rule { max_length: Some(7), allow_setgroups: Some(false), max_sandboxes: Some(2), ..Default::default() };
+        let defaults = Rule { max_length: Some(9), max_sandboxes: Some(3), subids: Some(false), ..Default::default() };
+        let policy = Policy {
+            defaults,
+            users: BTreeMap::from([(UID.to_string(), user)]),
+            groups: BTreeMap::from([(GID.to_string(), group)]),
+            ..Default::default()
+        };
+
+        let rule = policy.rule(&Caller { pid: 1, uid: UID, gid: GID });
+        assert_eq!(rule.max_length, Some(5));
+        assert_eq!(rule.allow_setgroups, Some(false));
+        assert_eq!(rule.max_sandboxes, Some(2));
+        assert_eq!(rule.subids, Some(false));
+
+        let rule = policy.rule(&Caller { pid: 1, uid: UID + 2, gid: GID });
+        assert_eq!(rule.max_length, Some(7));
+
+        let rule = policy.rule(&Caller { pid: 1, uid: UID + 2, gid: GID + 2 });
+        assert_eq!(rule.max_length, Some(9));
+        assert_eq!(rule.allow_setgroups, None);
+        assert_eq!(rule.max_sandboxes, Some(3));
+    }
+}
@@ -0,0 +1,731 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sched::CloneFlags;
+use nix::sys::signal::{SigHandler, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{ForkResult, Gid, Pid, Uid};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::watch;
+use tonic::Status;
+
+use crate::audit::{self, AuditLog, AuditRecord};
+use crate::backend::MapBackend;
+use crate::events::EventBus;
+use crate::grpc_handler::userns::{event, SandboxEvent};
+use crate::idmap::IdRange;
+use crate::metrics::Metrics;
+use crate::policy::{Caller, IdKind};
+use crate::stdio::{self, SandboxIo, ServerStdio};
+use crate::target::{self, Target};
+
+const STACK_SIZE: usize = 1024 * 1024;
+
+/// PATH used when the spawn request doesn't carry one
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// How a sandbox terminated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled(i32),
+    /// Reaping failed, so how it ended is lost
+    Unknown,
+}
+
+/// What to launch, after the request has been checked against policy
+#[derive(Debug, Clone)]
+pub struct SpawnSpec {
+    pub flags: CloneFlags,
+    pub argv: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub cwd: String,
+    pub uid_map: Vec<IdRange>,
+    pub gid_map: Vec<IdRange>,
+    pub allow_setgroups: bool,
+    /// Allocate a PTY instead of pipes for stdio
+    pub tty: bool,
+    /// Initial PTY size as (rows, columns)
+    pub window_size: Option<(u16, u16)>,
+}
+
+/// Sandbox launched by the server
+pub struct Sandbox {
+    pub id: String,
+    pub pid: Pid,
+    pub owner_uid: u32,
+    pub argv: Vec<String>,
+    pub io: Arc<SandboxIo>,
+    pidfd: OwnedFd,
+    status: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl Sandbox {
+    /// Exit status, or None while running
+    pub fn status(&self) -> Option<ExitStatus> {
+        *self.status.borrow()
+    }
+
+    /// Wait until the sandbox exits
+    pub async fn wait(&self) -> ExitStatus {
+        let mut status = self.status.clone();
+        loop {
+            if let Some(exit_status) = *status.borrow_and_update() {
+                return exit_status;
+            }
+            if status.changed().await.is_err() {
+                // reaper is gone, so the last value is final
+                return status.borrow().unwrap_or(ExitStatus::Unknown);
+            }
+        }
+    }
+
+    /// Send signal through the pidfd, so a recycled PID can't be hit
+    pub fn kill(&self, signal: Signal) -> Result<(), Status> {
+        let res = unsafe {
+            nix::libc::syscall(nix::libc::SYS_pidfd_send_signal, self.pidfd.as_raw_fd(), signal as i32, std::ptr::null::<()>(), 0)
+        };
+        match Errno::result(res) {
+            Ok(_) => Ok(()),
+            Err(Errno::ESRCH) => Err(Status::new(tonic::Code::FailedPrecondition, "sandbox has already exited")),
+            Err(err) => Err(Status::new(tonic::Code::Internal, format!("pidfd_send_signal: {}", err))),
+        }
+    }
+}
+
+/// Steps of the cloned child, reported to the server when one fails
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Stage {
+    Stdio = 1,
+    Setsid,
+    Terminal,
+    Credentials,
+    Unshare,
+    Sync,
+    IdSwitch,
+    Fork,
+    Chdir,
+    Signals,
+    Exec,
+}
+
+impl Stage {
+    fn from_u8(value: u8) -> Option<Self> {
+        [Stage::Stdio, Stage::Setsid, Stage::Terminal, Stage::Credentials, Stage::Unshare,
+         Stage::Sync, Stage::IdSwitch, Stage::Fork, Stage::Chdir, Stage::Signals, Stage::Exec]
+            .into_iter()
+            .find(|s| *s as u8 == value)
+    }
+}
+
+/// Everything the cloned child needs, prepared before clone(2)
+///
+/// The server is multi-threaded, so the child must not allocate: another
+/// thread may have held the allocator lock at the time of clone. For the
+/// same reason, it avoids libc wrappers which involve the other threads.
+///
+/// argv / envp point into CStrings owned by the caller of clone(2).
+struct ChildPlan {
+    path: CString,
+    argv_ptrs: Vec<*const nix::libc::c_char>,
+    envp_ptrs: Vec<*const nix::libc::c_char>,
+    cwd: CString,
+    /// Namespaces created with unshare(2) after switching credentials
+    unshare_flags: CloneFlags,
+    /// unshare_flags has a PID namespace, which only children enter
+    pid_ns: bool,
+    /// Caller's credentials to switch to, if they differ from ours
+    credentials: Option<(Uid, Gid, Vec<nix::libc::gid_t>)>,
+    /// IDs to switch to inside the new user namespace
+    inside_ids: Option<(Uid, Gid)>,
+    drop_groups: bool,
+    /// Become stdin, stdout and stderr
+    stdio: [RawFd; 3],
+    /// stdin is a PTY slave to make the controlling terminal
+    tty: bool,
+    /// parent -> child: maps have been written
+    release_r: RawFd,
+    /// child -> parent: namespaces are ready (0) or failure (1, stage, errno)
+    report_w: RawFd,
+}
+
+fn null_terminated(strings: &[CString]) -> Vec<*const nix::libc::c_char> {
+    strings.iter().map(|s| s.as_ptr()).chain(std::iter::once(std::ptr::null())).collect()
+}
+
+/// Look argv[0] up in PATH, unless it contains a slash
+fn resolve_command(command: &str, path_env: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return Some(PathBuf::from(command));
+    }
+    path_env
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(command))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+/// Inside ID the caller's own outside ID maps to, or the lowest mapped one
+fn inside_id(ranges: &[IdRange], own: u32) -> Option<u32> {
+    ranges
+        .iter()
+        .find(|r| own >= r.outside && (own - r.outside) < r.length)
+        .map(|r| r.inside + (own - r.outside))
+        .or_else(|| ranges.iter().map(|r| r.inside).min())
+}
+
+fn to_cstring(value: &str) -> Result<CString, Status> {
+    CString::new(value).map_err(|_| Status::new(tonic::Code::InvalidArgument, "string contains NUL byte"))
+}
+
+fn child_fail(report_w: RawFd, stage: Stage, err: Errno) -> isize {
+    let errno = (err as i32).to_ne_bytes();
+    let msg = [1, stage as u8, errno[0], errno[1], errno[2], errno[3]];
+    let _ = nix::unistd::write(report_w, &msg);
+    127
+}
+
+/// Switch to uid and gid, and groups if given, with raw syscalls
+///
+/// The libc wrappers make every thread of the process switch along, which
+/// a child cloned from the multi-threaded server must not wait for.
+fn switch_ids(groups: Option<&[nix::libc::gid_t]>, uid: Uid, gid: Gid) -> nix::Result<()> {
+    if let Some(groups) = groups {
+        Errno::result(unsafe { nix::libc::syscall(nix::libc::SYS_setgroups, groups.len(), groups.as_ptr()) })?;
+    }
+    Errno::result(unsafe { nix::libc::syscall(nix::libc::SYS_setresgid, gid.as_raw(), gid.as_raw(), gid.as_raw()) })?;
+    Errno::result(unsafe { nix::libc::syscall(nix::libc::SYS_setresuid, uid.as_raw(), uid.as_raw(), uid.as_raw()) })?;
+    Ok(())
+}
+
+/// fork(2) as a raw clone(2), without the atfork handlers of the libc
+/// wrapper, which take locks other threads of the server may have held
+fn fork() -> nix::Result<ForkResult> {
+    let res = unsafe { nix::libc::syscall(nix::libc::SYS_clone, nix::libc::SIGCHLD, 0, 0, 0, 0) };
+    Errno::result(res).map(|pid| match pid {
+        0 => ForkResult::Child,
+        pid => ForkResult::Parent { child: Pid::from_raw(pid as nix::libc::pid_t) },
+    })
+}
+
+/// Leave the command default signal dispositions and an empty mask, rather
+/// than what the server ignores or blocks
+fn reset_signals() -> nix::Result<()> {
+    for signum in 1..=nix::libc::SIGRTMAX() {
+        // fails for SIGKILL, SIGSTOP and those reserved by libc, which
+        // can't have been changed anyway
+        unsafe { nix::libc::signal(signum, nix::libc::SIG_DFL) };
+    }
+    SigSet::empty().thread_set_mask()
+}
+
+/// Body of the cloned sandbox process; only returns on failure
+fn child_main(plan: &ChildPlan) -> isize {
+    for (target, fd) in plan.stdio.iter().enumerate() {
+        if let Err(err) = nix::unistd::dup2(*fd, target as RawFd) {
+            return child_fail(plan.report_w, Stage::Stdio, err);
+        }
+    }
+    if let Err(err) = nix::unistd::setsid() {
+        return child_fail(plan.report_w, Stage::Setsid, err);
+    }
+    if plan.tty {
+        if let Err(err) = Errno::result(unsafe { nix::libc::ioctl(0, nix::libc::TIOCSCTTY, 0) }) {
+            return child_fail(plan.report_w, Stage::Terminal, err);
+        }
+    }
+    if let Some((uid, gid, groups)) = &plan.credentials {
+        if let Err(err) = switch_ids(Some(groups), *uid, *gid) {
+            return child_fail(plan.report_w, Stage::Credentials, err);
+        }
+    }
+    if let Err(err) = nix::sched::unshare(plan.unshare_flags) {
+        return child_fail(plan.report_w, Stage::Unshare, err);
+    }
+    if let Err(err) = nix::unistd::write(plan.report_w, &[0]) {
+        return child_fail(plan.report_w, Stage::Sync, err);
+    }
+    let mut buf = [0u8; 1];
+    match nix::unistd::read(plan.release_r, &mut buf) {
+        Ok(1) => {},
+        Ok(_) => return child_fail(plan.report_w, Stage::Sync, Errno::EPIPE),
+        Err(err) => return child_fail(plan.report_w, Stage::Sync, err),
+    }
+    if let Some((uid, gid)) = plan.inside_ids {
+        // setgroups(2) first, capabilities are lost when leaving UID 0
+        if let Err(err) = switch_ids(plan.drop_groups.then_some(&[]), uid, gid) {
+            return child_fail(plan.report_w, Stage::IdSwitch, err);
+        }
+    }
+    if plan.pid_ns {
+        // signals for the command are taken by sigwait(2) until it runs
+        if let Err(err) = nix::sys::signal::pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&forwarded_signals()), None) {
+            return child_fail(plan.report_w, Stage::Fork, err);
+        }
+        match fork() {
+            Ok(ForkResult::Child) => {
+                // init of the PID namespace, so all of it goes if we're killed
+                if let Err(err) = Errno::result(unsafe { nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGKILL) }) {
+                    return child_fail(plan.report_w, Stage::Fork, err);
+                }
+            },
+            Ok(ForkResult::Parent { child }) => return supervise(plan, child),
+            Err(err) => return child_fail(plan.report_w, Stage::Fork, err),
+        }
+    }
+    if let Err(err) = Errno::result(unsafe { nix::libc::chdir(plan.cwd.as_ptr()) }) {
+        return child_fail(plan.report_w, Stage::Chdir, err);
+    }
+    if let Err(err) = reset_signals() {
+        return child_fail(plan.report_w, Stage::Signals, err);
+    }
+    unsafe { nix::libc::execve(plan.path.as_ptr(), plan.argv_ptrs.as_ptr(), plan.envp_ptrs.as_ptr()) };
+    child_fail(plan.report_w, Stage::Exec, Errno::last())
+}
+
+/// Signals passed on to the init of a PID namespace, plus SIGCHLD
+fn forwarded_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    for signal in [Signal::SIGHUP, Signal::SIGINT, Signal::SIGQUIT, Signal::SIGTERM, Signal::SIGUSR1, Signal::SIGUSR2, Signal::SIGCHLD] {
+        signals.add(signal);
+    }
+    signals
+}
+
+/// Stand in for init of the sandbox's PID namespace: forward signals to
+/// it and exit the way it does
+///
+/// Its parent is outside of the namespace, so it's what the server waits
+/// for and signals.
+fn supervise(plan: &ChildPlan, init: Pid) -> isize {
+    // the command reports exec(2) and holds stdio on its own
+    for fd in [plan.report_w, plan.release_r, 0, 1, 2] {
+        let _ = nix::unistd::close(fd);
+    }
+    let signals = forwarded_signals();
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => {},
+            Ok(signal) => {
+                let _ = nix::sys::signal::kill(init, signal);
+                continue;
+            },
+            Err(_) => continue,
+        }
+        match waitpid(init, Some(nix::sys::wait::WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, code)) => return code as isize,
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                unsafe {
+                    let _ = nix::sys::signal::signal(signal, SigHandler::SigDfl);
+                }
+                let mut unblock = SigSet::empty();
+                unblock.add(signal);
+                let _ = unblock.thread_unblock();
+                let _ = nix::sys::signal::raise(signal);
+                return 128 + signal as isize;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Status for a failure of the cloned child
+fn stage_status(stage: Option<Stage>, errno: Errno) -> Status {
+    let code = match errno {
+        Errno::EPERM | Errno::EACCES => tonic::Code::PermissionDenied,
+        Errno::ENOENT | Errno::ENOTDIR => tonic::Code::NotFound,
+        Errno::EINVAL => tonic::Code::InvalidArgument,
+        _ => tonic::Code::Internal,
+    };
+    let stage = stage.map_or_else(|| "unknown stage".to_string(), |s| format!("{:?}", s));
+    Status::new(code, format!("sandbox failed at {}: {}", stage, errno))
+}
+
+/// Read a failure report (stage, errno) following the leading 1 byte
+fn read_failure(report_r: RawFd) -> Status {
+    let mut msg = [0u8; 5];
+    match nix::unistd::read(report_r, &mut msg) {
+        Ok(5) => stage_status(Stage::from_u8(msg[0]), Errno::from_i32(i32::from_ne_bytes([msg[1], msg[2], msg[3], msg[4]]))),
+        _ => Status::new(tonic::Code::Internal, "sandbox failed without report"),
+    }
+}
+
+/// Write maps of the sandbox's user namespace (GID first, needs setgroups)
+///
+/// Each map write is recorded in the audit log, if there's one.
+fn write_maps(pid: Pid, spec: &SpawnSpec, caller: &Caller, sandbox_id: &str, audit: Option<&AuditLog>, backend: &dyn MapBackend) -> std::io::Result<()> {
+    let setgroups = if spec.allow_setgroups { "allow" } else { "deny" };
+    let target = Target::open(pid.as_raw() as u32)?;
+    // the sandbox isn't reaped before this returns, so it's still this namespace
+    let user_ns_inode = target.user_ns_inode().ok();
+    let record = |kind: IdKind, ranges: &[IdRange], res: &std::io::Result<()>| {
+        if let Some(audit) = audit {
+            let mut record = AuditRecord::new(audit::Source::Spawn, caller, pid.as_raw() as u32, kind, ranges.to_vec());
+            record.sandbox_id = Some(sandbox_id.to_string());
+            if kind == IdKind::Gid {
+                record.setgroups = Some(setgroups);
+            }
+            record.inspect(&target, user_ns_inode);
+            record.set_result(&res.as_ref().map(drop).map_err(|err| Status::new(tonic::Code::Internal, err.to_string())));
+            audit.write(&record);
+        }
+    };
+    let res = backend.write_map(&target, IdKind::Gid, &spec.gid_map, spec.allow_setgroups);
+    record(IdKind::Gid, &spec.gid_map, &res);
+    res?;
+    let res = backend.write_map(&target, IdKind::Uid, &spec.uid_map, false);
+    record(IdKind::Uid, &spec.uid_map, &res);
+    res
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), Status> {
+    let (r, w) = nix::unistd::pipe2(OFlag::O_CLOEXEC)
+        .map_err(|err| Status::new(tonic::Code::Internal, format!("pipe: {}", err)))?;
+    Ok(unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) })
+}
+
+/// Clone the sandbox, write its maps and let it exec
+///
+/// Blocks on pipes, so it's run with spawn_blocking.
+fn spawn_blocking(caller: Caller, spec: &SpawnSpec, id: &str, audit: Option<&AuditLog>, backend: &dyn MapBackend) -> Result<(Pid, OwnedFd, ServerStdio), Status> {
+    let mut env: Vec<(String, String)> = spec.env.clone();
+    if !env.iter().any(|(k, _)| k == "PATH") {
+        env.push(("PATH".to_string(), DEFAULT_PATH.to_string()));
+    }
+    let path_env = env.iter().find(|(k, _)| k == "PATH").map(|(_, v)| v.clone()).unwrap_or_default();
+    let command = spec.argv.first().ok_or_else(|| Status::new(tonic::Code::InvalidArgument, "empty argv"))?;
+    let path = resolve_command(command, &path_env)
+        .ok_or_else(|| Status::new(tonic::Code::NotFound, format!("command not found: {}", command)))?;
+    let argv = spec.argv.iter().map(|a| to_cstring(a)).collect::<Result<Vec<_>, _>>()?;
+    let envp = env.iter().map(|(k, v)| to_cstring(&format!("{}={}", k, v))).collect::<Result<Vec<_>, _>>()?;
+    let user_ns = spec.flags.contains(CloneFlags::CLONE_NEWUSER);
+    // run with the caller's identity, unless it's ours already
+    let credentials = if caller.uid != nix::unistd::geteuid().as_raw() || caller.gid != nix::unistd::getegid().as_raw() {
+        let uid = Uid::from_raw(caller.uid);
+        let gid = Gid::from_raw(caller.gid);
+        let groups = match nix::unistd::User::from_uid(uid) {
+            Ok(Some(user)) => nix::unistd::getgrouplist(&to_cstring(&user.name)?, gid).unwrap_or_else(|_| vec![gid]),
+            _ => vec![gid],
+        };
+        Some((uid, gid, groups.into_iter().map(|g| g.as_raw()).collect()))
+    } else {
+        None
+    };
+    let inside_ids = if user_ns {
+        match (inside_id(&spec.uid_map, caller.uid), inside_id(&spec.gid_map, caller.gid)) {
+            (Some(uid), Some(gid)) => Some((Uid::from_raw(uid), Gid::from_raw(gid))),
+            _ => return Err(Status::new(tonic::Code::InvalidArgument, "empty mapping")),
+        }
+    } else {
+        None
+    };
+    let window_size = spec.window_size.map(|(rows, cols)| stdio::window_size(rows, cols));
+    let (child_stdio, server_stdio) = stdio::open(spec.tty, window_size, Uid::from_raw(caller.uid))?;
+    let (release_r, release_w) = pipe()?;
+    let (report_r, report_w) = pipe()?;
+    let argv_ptrs = null_terminated(&argv);
+    let envp_ptrs = null_terminated(&envp);
+    let plan = ChildPlan {
+        path: CString::new(path.as_os_str().as_bytes()).unwrap(),
+        argv_ptrs,
+        envp_ptrs,
+        cwd: to_cstring(if spec.cwd.is_empty() { "/" } else { &spec.cwd })?,
+        // all at once, so the PID namespace is owned by the new user namespace
+        unshare_flags: spec.flags,
+        pid_ns: spec.flags.contains(CloneFlags::CLONE_NEWPID),
+        credentials,
+        inside_ids,
+        drop_groups: user_ns && spec.allow_setgroups,
+        stdio: [child_stdio.stdin.as_raw_fd(), child_stdio.stdout.as_raw_fd(), child_stdio.stderr.as_raw_fd()],
+        tty: child_stdio.tty,
+        release_r: release_r.as_raw_fd(),
+        report_w: report_w.as_raw_fd(),
+    };
+    debug!("spawn {:?} ({:?}) for {:?}", plan.path, spec.argv, caller);
+    let mut stack = vec![0u8; STACK_SIZE];
+    let cb = Box::new(|| child_main(&plan));
+    let pid = nix::sched::clone(cb, &mut stack, CloneFlags::empty(), Some(Signal::SIGCHLD as i32))
+        .map_err(|err| stage_status(None, err))?;
+    drop(report_w);
+    drop(release_r);
+    // the child has its own copies, and the output must see EOF once it exits
+    drop(child_stdio);
+    // child can't be reaped before we wait for it, so the PID is still ours
+    let pidfd = match target::pidfd_open(pid.as_raw() as u32) {
+        Ok(pidfd) => pidfd,
+        Err(err) => {
+            let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+            return Err(Status::new(tonic::Code::Internal, format!("pidfd_open: {}", err)));
+        }
+    };
+    let abort = |status: Status| {
+        let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+        let _ = waitpid(pid, None);
+        Err(status)
+    };
+    let mut buf = [0u8; 1];
+    match nix::unistd::read(report_r.as_raw_fd(), &mut buf) {
+        Ok(1) if buf[0] == 0 => {},
+        Ok(1) => return abort(read_failure(report_r.as_raw_fd())),
+        _ => return abort(Status::new(tonic::Code::Internal, "sandbox exited before setup")),
+    }
+    if user_ns {
+        if let Err(err) = write_maps(pid, spec, &caller, id, audit, backend) {
+            warn!("write maps of {} failed: {}", pid, err);
+            return abort(Status::new(tonic::Code::Internal, format!("write maps: {}", err)));
+        }
+    }
+    if let Err(err) = nix::unistd::write(release_w.as_raw_fd(), &[1]) {
+        return abort(Status::new(tonic::Code::Internal, format!("release sandbox: {}", err)));
+    }
+    drop(release_w);
+    // report pipe is close-on-exec: EOF means execve(2) succeeded
+    match nix::unistd::read(report_r.as_raw_fd(), &mut buf) {
+        Ok(0) => Ok((pid, pidfd, server_stdio)),
+        Ok(_) => {
+            let status = read_failure(report_r.as_raw_fd());
+            let _ = waitpid(pid, None);
+            Err(status)
+        },
+        Err(err) => abort(Status::new(tonic::Code::Internal, format!("read sandbox report: {}", err))),
+    }
+}
+
+/// Copy of pidfd for the reaper, which polls it
+fn reaper_fd(pidfd: &OwnedFd) -> std::io::Result<AsyncFd<OwnedFd>> {
+    // the copy is moved in, so nothing else can close or replace it
+    Ok(unsafe { AsyncFd::register_with_interest(pidfd.try_clone()?, Interest::READABLE)? })
+}
+
+/// Wait until the process behind pidfd exits and reap it
+///
+/// The pidfd turns readable once the process has exited, so no thread
+/// is kept blocked in waitpid(2) meanwhile.
+async fn reap(pidfd: &AsyncFd<OwnedFd>) -> std::io::Result<ExitStatus> {
+    loop {
+        let mut guard = pidfd.readable().await?;
+        let mut info: nix::libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            nix::libc::waitid(nix::libc::P_PIDFD, pidfd.as_raw_fd() as nix::libc::id_t, &mut info, nix::libc::WEXITED | nix::libc::WNOHANG)
+        };
+        match Errno::result(res) {
+            Ok(_) => {},
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+        // WNOHANG leaves si_pid zeroed while the process is still running
+        if unsafe { info.si_pid() } == 0 {
+            guard.clear_ready();
+            continue;
+        }
+        let status = unsafe { info.si_status() };
+        return Ok(match info.si_code {
+            nix::libc::CLD_EXITED => ExitStatus::Exited(status),
+            _ => ExitStatus::Signaled(status),
+        });
+    }
+}
+
+fn sandbox_event(id: &str, pid: Pid, argv: &[String], status: Option<ExitStatus>) -> event::Detail {
+    let (exit_code, signal) = match status {
+        Some(ExitStatus::Exited(code)) => (code, 0),
+        Some(ExitStatus::Signaled(signal)) => (0, signal),
+        Some(ExitStatus::Unknown) => (-1, 0),
+        None => (0, 0),
+    };
+    event::Detail::Sandbox(SandboxEvent {
+        sandbox_id: id.to_string(),
+        pid: pid.as_raw() as u32,
+        argv: argv.to_vec(),
+        exit_code,
+        signal,
+    })
+}
+
+/// Sandboxes launched by the server, keyed by ID
+pub struct SandboxManager {
+    sandboxes: Mutex<HashMap<String, Arc<Sandbox>>>,
+    next_id: AtomicU64,
+    events: EventBus,
+    audit: Option<Arc<AuditLog>>,
+    backend: Arc<dyn MapBackend>,
+    metrics: Arc<Metrics>,
+    /// Sandboxes starting or running, per owner UID
+    active: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+fn release(active: &Mutex<HashMap<u32, usize>>, uid: u32) {
+    let mut active = active.lock().unwrap();
+    if let Some(count) = active.get_mut(&uid) {
+        *count -= 1;
+        if *count == 0 {
+            active.remove(&uid);
+        }
+    }
+}
+
+impl SandboxManager {
+    pub fn new(events: EventBus, audit: Option<Arc<AuditLog>>, backend: Arc<dyn MapBackend>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            sandboxes: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            events,
+            audit,
+            backend,
+            metrics,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Count a sandbox of uid as active, unless it has limit ones already
+    fn reserve(&self, uid: u32, limit: Option<usize>) -> Result<(), Status> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(uid).or_default();
+        if limit.is_some_and(|limit| *count >= limit) {
+            return Err(Status::new(
+                tonic::Code::ResourceExhausted,
+                format!("UID {} already has {} running sandboxes", uid, count),
+            ));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// Launch a sandbox for caller and start reaping it in background
+    ///
+    /// Fails with ResourceExhausted if caller already has limit sandboxes running.
+    pub async fn spawn(&self, caller: Caller, spec: SpawnSpec, limit: Option<usize>) -> Result<Arc<Sandbox>, Status> {
+        self.reserve(caller.uid, limit)?;
+        let res = self.start(caller, spec).await;
+        if res.is_err() {
+            release(&self.active, caller.uid);
+        }
+        res
+    }
+
+    async fn start(&self, caller: Caller, spec: SpawnSpec) -> Result<Arc<Sandbox>, Status> {
+        let argv = spec.argv.clone();
+        let id = format!("sandbox-{}", self.next_id.fetch_add(1, Ordering::SeqCst) + 1);
+        let audit = self.audit.clone();
+        let backend = self.backend.clone();
+        let spawn_id = id.clone();
+        let (pid, pidfd, server_stdio) = tokio::task::spawn_blocking(move || spawn_blocking(caller, &spec, &spawn_id, audit.as_deref(), backend.as_ref()))
+            .await
+            .map_err(|err| Status::new(tonic::Code::Internal, format!("spawn task: {}", err)))??;
+        let started = reaper_fd(&pidfd)
+            .map_err(|err| format!("pidfd: {}", err))
+            .and_then(|reaper_fd| Ok((reaper_fd, SandboxIo::start(server_stdio).map_err(|err| format!("sandbox stdio: {}", err))?)));
+        let (reaper_fd, io) = match started {
+            Ok(started) => started,
+            Err(err) => {
+                let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+                let _ = tokio::task::spawn_blocking(move || waitpid(pid, None)).await;
+                return Err(Status::new(tonic::Code::Internal, err));
+            },
+        };
+        let (status_tx, status_rx) = watch::channel(None);
+        let sandbox = Arc::new(Sandbox { id: id.clone(), pid, owner_uid: caller.uid, argv, io, pidfd, status: status_rx });
+        info!("sandbox {} started as PID {}", id, pid);
+        self.events.publish(event::Kind::SandboxStarted, Some(&caller), Some(sandbox_event(&id, pid, &sandbox.argv, None)));
+        let events = self.events.clone();
+        let active = self.active.clone();
+        let metrics = self.metrics.clone();
+        metrics.sandbox_started();
+        let argv = sandbox.argv.clone();
+        tokio::spawn(async move {
+            // however the reaper ends, the slot is freed, the sandbox stops
+            // counting as active and waiters get a status
+            let mut reaped = scopeguard::guard(None, |status: Option<ExitStatus>| {
+                release(&active, caller.uid);
+                metrics.sandbox_exited();
+                let _ = status_tx.send(Some(status.unwrap_or(ExitStatus::Unknown)));
+            });
+            let status = reap(&reaper_fd).await.unwrap_or_else(|err| {
+                error!("waitid {} failed: {}", pid, err);
+                ExitStatus::Unknown
+            });
+            match status {
+                ExitStatus::Exited(code) => info!("sandbox {} exited with {}", id, code),
+                ExitStatus::Signaled(signal) => info!("sandbox {} killed by signal {}", id, signal),
+                ExitStatus::Unknown => {},
+            }
+            *reaped = Some(status);
+            drop(reaped);
+            events.publish(event::Kind::SandboxExited, Some(&caller), Some(sandbox_event(&id, pid, &argv, Some(status))));
+        });
+        self.sandboxes.lock().unwrap().insert(sandbox.id.clone(), sandbox.clone());
+        Ok(sandbox)
+    }
+
+    /// Sandboxes visible to caller (all of them for root)
+    pub fn list(&self, caller: &Caller) -> Vec<Arc<Sandbox>> {
+        let mut sandboxes: Vec<Arc<Sandbox>> = self
+            .sandboxes
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| caller.uid == 0 || s.owner_uid == caller.uid)
+            .cloned()
+            .collect();
+        sandboxes.sort_by_key(|s| s.pid.as_raw());
+        sandboxes
+    }
+
+    /// Look a sandbox up, checking that caller owns it
+    pub fn get(&self, caller: &Caller, id: &str) -> Result<Arc<Sandbox>, Status> {
+        let sandbox = self
+            .sandboxes
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, format!("sandbox {} not found", id)))?;
+        if caller.uid != 0 && sandbox.owner_uid != caller.uid {
+            return Err(Status::new(tonic::Code::PermissionDenied, "sandbox belongs to another user"));
+        }
+        Ok(sandbox)
+    }
+
+    /// Kill the sandboxes still running and wait until they're reaped
+    pub async fn shutdown(&self) {
+        let running: Vec<Arc<Sandbox>> = self.sandboxes.lock().unwrap().values().filter(|s| s.status().is_none()).cloned().collect();
+        if running.is_empty() {
+            return;
+        }
+        info!("killing {} running sandboxes", running.len());
+        for sandbox in &running {
+            // already exited if this fails, the reaper has it
+            let _ = sandbox.kill(Signal::SIGKILL);
+        }
+        for sandbox in &running {
+            sandbox.wait().await;
+        }
+    }
+
+    /// Forget an exited sandbox
+    pub fn remove(&self, caller: &Caller, id: &str) -> Result<(), Status> {
+        let sandbox = self.get(caller, id)?;
+        if sandbox.status().is_none() {
+            return Err(Status::new(tonic::Code::FailedPrecondition, "sandbox is still running"));
+        }
+        self.sandboxes.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
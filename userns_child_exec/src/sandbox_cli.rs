@@ -0,0 +1,412 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use clap::{Args, Subcommand};
+use nix::errno::Errno;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::termios::{self, SetArg, Termios};
+use tokio::sync::mpsc;
+
+use crate::grpc_client::userns::{attach_response, Sandbox, SpawnRequest, WindowSize};
+use crate::grpc_client::{to_map_ranges, AttachInput, UsernsClient};
+use crate::map_cli::MapArgs;
+use crate::policy::IdKind;
+
+/// Input frames read ahead of the server
+const INPUT_CAPACITY: usize = 64;
+
+#[derive(Args, Debug)]
+pub struct SpawnArgs {
+    /// New IPC Namespace
+    #[arg(short, long, default_value_t = false)]
+    ipc: bool,
+    /// New Mount Namespace
+    #[arg(short, long, default_value_t = false)]
+    mount: bool,
+    /// New Network Namespace
+    #[arg(short, long, default_value_t = false)]
+    network: bool,
+    /// New PID Namespace
+    #[arg(short, long, default_value_t = false)]
+    pid: bool,
+    /// New UTS Namespace
+    #[arg(short, long, default_value_t = false)]
+    uts: bool,
+    /// New USER Namespace
+    #[arg(short = 'U', long, default_value_t = false)]
+    user: bool,
+    #[command(flatten)]
+    map_args: MapArgs,
+    /// Keep setgroups(2) Usable in the USER Namespace
+    #[arg(long, default_value_t = false)]
+    allow_setgroups: bool,
+    /// Working Directory of the Sandbox
+    #[arg(long)]
+    cwd: Option<String>,
+    /// Environment Variable (KEY=VALUE), Replaces the Inherited Environment
+    #[arg(short, long, value_parser = parse_env)]
+    env: Vec<(String, String)>,
+    /// Allocate a PTY for the Sandbox
+    #[arg(short, long, default_value_t = false)]
+    tty: bool,
+    /// Attach to the Sandbox Once it's Spawned
+    #[arg(short, long, default_value_t = false)]
+    attach: bool,
+    #[command(flatten)]
+    attach_args: AttachArgs,
+    /// Command and Arguments
+    #[arg(required = true, last = true)]
+    argv: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct AttachArgs {
+    /// Key Sequence for Detaching (e.g. ctrl-p,ctrl-q; empty to disable)
+    #[arg(long, default_value = "ctrl-p,ctrl-q", value_parser = parse_detach_keys)]
+    detach_keys: DetachKeys,
+    /// Don't Forward Stdin
+    #[arg(long, default_value_t = false)]
+    no_stdin: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SandboxCommands {
+    /// Spawns a sandboxed process on the server
+    Spawn(SpawnArgs),
+    /// Lists sandboxes
+    List,
+    /// Attaches to stdio of a running sandbox
+    Attach {
+        /// Sandbox ID
+        id: String,
+        /// Replay Output Produced Before Attaching
+        #[arg(long, default_value_t = false)]
+        logs: bool,
+        #[command(flatten)]
+        attach_args: AttachArgs,
+    },
+    /// Waits until sandbox exits
+    Wait {
+        /// Sandbox ID
+        id: String,
+    },
+    /// Sends a signal to sandbox
+    Kill {
+        /// Sandbox ID
+        id: String,
+        /// Signal Name or Number
+        #[arg(short, long, default_value = "SIGTERM", value_parser = parse_signal)]
+        signal: Signal,
+    },
+    /// Removes an exited sandbox
+    Rm {
+        /// Sandbox ID
+        id: String,
+    },
+}
+
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE: {}", value)),
+    }
+}
+
+/// Parse `TERM`, `SIGTERM` or `15`
+fn parse_signal(value: &str) -> Result<Signal, String> {
+    if let Ok(signal) = value.parse::<i32>() {
+        return Signal::try_from(signal).map_err(|_| format!("invalid signal: {}", value));
+    }
+    let name = value.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    Signal::from_str(&name).map_err(|_| format!("invalid signal: {}", value))
+}
+
+/// Byte sequence which detaches from a sandbox
+#[derive(Debug, Clone)]
+struct DetachKeys(Vec<u8>);
+
+/// Parse comma separated keys, each a single character or `ctrl-X`
+fn parse_detach_keys(value: &str) -> Result<DetachKeys, String> {
+    if value.is_empty() {
+        return Ok(DetachKeys(vec![]));
+    }
+    value
+        .split(',')
+        .map(|key| match key.strip_prefix("ctrl-") {
+            Some(c) if c.len() == 1 && matches!(c.as_bytes()[0], b'@'..=b'_' | b'a'..=b'z') => Ok(c.as_bytes()[0] & 0x1f),
+            None if key.len() == 1 => Ok(key.as_bytes()[0]),
+            _ => Err(format!("invalid detach key: {}", key)),
+        })
+        .collect::<Result<_, _>>()
+        .map(DetachKeys)
+}
+
+/// Size of the terminal on stdin as (rows, columns)
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size: nix::libc::winsize = unsafe { std::mem::zeroed() };
+    Errno::result(unsafe { nix::libc::ioctl(0, nix::libc::TIOCGWINSZ, &mut size) }).ok()?;
+    Some((size.ws_row, size.ws_col))
+}
+
+/// Switch the terminal on stdin to raw mode, returning settings to restore
+fn make_raw() -> Option<Termios> {
+    if !nix::unistd::isatty(0).unwrap_or(false) {
+        return None;
+    }
+    let original = termios::tcgetattr(0).ok()?;
+    let mut raw = original.clone();
+    termios::cfmakeraw(&mut raw);
+    termios::tcsetattr(0, SetArg::TCSANOW, &raw).ok()?;
+    Some(original)
+}
+
+/// Forward stdin until EOF or the detach keys are typed
+fn read_stdin(tx: mpsc::Sender<AttachInput>, detach_keys: DetachKeys) {
+    let keys = detach_keys.0;
+    let mut buf = [0u8; 4096];
+    // length of the detach key prefix held back
+    let mut matched = 0;
+    loop {
+        let n = match std::io::stdin().lock().read(&mut buf) {
+            Ok(0) => {
+                let _ = tx.blocking_send(AttachInput::CloseStdin);
+                return;
+            },
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                warn!("read stdin: {}", err);
+                return;
+            },
+        };
+        let mut data = vec![];
+        for &byte in &buf[..n] {
+            if matched < keys.len() && byte == keys[matched] {
+                matched += 1;
+                if matched == keys.len() {
+                    if !data.is_empty() {
+                        let _ = tx.blocking_send(AttachInput::Stdin(data));
+                    }
+                    let _ = tx.blocking_send(AttachInput::Detach);
+                    return;
+                }
+                continue;
+            }
+            data.extend_from_slice(&keys[..matched]);
+            matched = 0;
+            if !keys.is_empty() && byte == keys[0] {
+                matched = 1;
+            } else {
+                data.push(byte);
+            }
+        }
+        if !data.is_empty() && tx.blocking_send(AttachInput::Stdin(data)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Forward terminal size changes (SIGWINCH must be blocked by the caller)
+fn forward_resize(tx: mpsc::Sender<AttachInput>) {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGWINCH);
+    while mask.wait().is_ok() {
+        if let Some((rows, cols)) = terminal_size() {
+            if tx.blocking_send(AttachInput::Resize(rows, cols)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Exit code of the attach command, following the sandbox like shells do
+fn exit_code(sandbox: &Sandbox) -> ExitCode {
+    if sandbox.signal != 0 {
+        ExitCode::from((128 + sandbox.signal) as u8)
+    } else {
+        ExitCode::from(sandbox.exit_code as u8)
+    }
+}
+
+fn attach(client: &mut UsernsClient, id: &str, logs: bool, tty: bool, args: &AttachArgs) -> ExitCode {
+    let (tx, rx) = mpsc::channel(INPUT_CAPACITY);
+    let raw = if tty { make_raw() } else { None };
+    defer! {
+        if let Some(original) = &raw {
+            let _ = termios::tcsetattr(0, SetArg::TCSANOW, original);
+        }
+    }
+    if raw.is_some() {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGWINCH);
+        // blocked before any thread is started, so it's only seen by sigwait
+        if let Err(err) = mask.thread_block() {
+            warn!("block SIGWINCH: {}", err);
+        }
+        if let Some((rows, cols)) = terminal_size() {
+            let _ = tx.try_send(AttachInput::Resize(rows, cols));
+        }
+        let tx = tx.clone();
+        std::thread::spawn(move || forward_resize(tx));
+    }
+    if !args.no_stdin {
+        let detach_keys = args.detach_keys.clone();
+        std::thread::spawn(move || read_stdin(tx, detach_keys));
+    } else {
+        drop(tx);
+    }
+    let res = client.attach(id, logs, rx, |frame| {
+        let res = match frame {
+            attach_response::Frame::Stdout(data) => std::io::stdout().write_all(&data).and_then(|_| std::io::stdout().flush()),
+            attach_response::Frame::Stderr(data) => std::io::stderr().write_all(&data),
+            attach_response::Frame::Exit(_) => Ok(()),
+        };
+        if let Err(err) = res {
+            warn!("write output: {}", err);
+        }
+    });
+    match res {
+        Ok(Some(sandbox)) => exit_code(&sandbox),
+        Ok(None) => {
+            if raw.is_some() {
+                print!("\r\n");
+            }
+            println!("detached from {}", id);
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            println!("got error while attach: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn state(sandbox: &Sandbox) -> String {
+    if sandbox.running {
+        "running".to_string()
+    } else if sandbox.signal != 0 {
+        match Signal::try_from(sandbox.signal) {
+            Ok(signal) => format!("killed by {}", signal),
+            Err(_) => format!("killed by signal {}", sandbox.signal),
+        }
+    } else if sandbox.exit_code < 0 {
+        "status unknown".to_string()
+    } else {
+        format!("exited with {}", sandbox.exit_code)
+    }
+}
+
+/// Runs a sandbox subcommand against the server at socket_path
+pub fn run(command: &SandboxCommands, socket_path: &Path) -> ExitCode {
+    let mut client = match UsernsClient::connect(socket_path) {
+        Ok(client) => client,
+        Err(err) => {
+            println!("got error while connect: {}", err);
+            return ExitCode::from(1)
+        }
+    };
+    match command {
+        SandboxCommands::Spawn(args) => {
+            let env = if args.env.is_empty() { std::env::vars().collect() } else { args.env.iter().cloned().collect() };
+            let maps = if args.user {
+                args.map_args.ranges(IdKind::Uid).and_then(|uid_map| Ok((uid_map, args.map_args.ranges(IdKind::Gid)?)))
+            } else {
+                Ok((vec![], vec![]))
+            };
+            let (uid_map, gid_map) = match maps {
+                Ok(maps) => maps,
+                Err(err) => {
+                    println!("invalid mapping: {}", err);
+                    return ExitCode::from(2)
+                }
+            };
+            let request = SpawnRequest {
+                ipc: args.ipc,
+                mount: args.mount,
+                network: args.network,
+                pid: args.pid,
+                uts: args.uts,
+                user: args.user,
+                argv: args.argv.clone(),
+                env,
+                cwd: args.cwd.clone().unwrap_or_default(),
+                uid_map: to_map_ranges(&uid_map),
+                gid_map: to_map_ranges(&gid_map),
+                allow_setgroups: args.allow_setgroups,
+                tty: args.tty,
+                window_size: if args.tty { terminal_size().map(|(rows, cols)| WindowSize { rows: rows as u32, cols: cols as u32 }) } else { None },
+            };
+            match client.spawn(request) {
+                Ok(response) if args.attach => attach(&mut client, &response.sandbox_id, true, args.tty, &args.attach_args),
+                Ok(response) => {
+                    println!("{} {}", response.sandbox_id, response.pid);
+                    ExitCode::SUCCESS
+                },
+                Err(err) => {
+                    println!("got error while spawn: {}", err);
+                    ExitCode::from(2)
+                }
+            }
+        },
+        SandboxCommands::List => match client.list() {
+            Ok(sandboxes) => {
+                println!("{:<16} {:>8} {:>8} {:<24} COMMAND", "ID", "PID", "OWNER", "STATE");
+                for sandbox in sandboxes {
+                    println!(
+                        "{:<16} {:>8} {:>8} {:<24} {}",
+                        sandbox.sandbox_id,
+                        sandbox.pid,
+                        sandbox.owner_uid,
+                        state(&sandbox),
+                        sandbox.argv.join(" ")
+                    );
+                }
+                ExitCode::SUCCESS
+            },
+            Err(err) => {
+                println!("got error while list: {}", err);
+                ExitCode::from(2)
+            }
+        },
+        SandboxCommands::Attach { id, logs, attach_args } => match client.list() {
+            Ok(sandboxes) => match sandboxes.iter().find(|s| &s.sandbox_id == id) {
+                Some(sandbox) => attach(&mut client, id, *logs, sandbox.tty, attach_args),
+                None => {
+                    println!("sandbox {} not found", id);
+                    ExitCode::from(2)
+                }
+            },
+            Err(err) => {
+                println!("got error while list: {}", err);
+                ExitCode::from(2)
+            }
+        },
+        SandboxCommands::Wait { id } => match client.wait(id) {
+            Ok(sandbox) => {
+                println!("{} {}", sandbox.sandbox_id, state(&sandbox));
+                ExitCode::SUCCESS
+            },
+            Err(err) => {
+                println!("got error while wait: {}", err);
+                ExitCode::from(2)
+            }
+        },
+        SandboxCommands::Kill { id, signal } => match client.kill(id, *signal as i32) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                println!("got error while kill: {}", err);
+                ExitCode::from(2)
+            }
+        },
+        SandboxCommands::Rm { id } => match client.remove(id) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                println!("got error while remove: {}", err);
+                ExitCode::from(2)
+            }
+        },
+    }
+}
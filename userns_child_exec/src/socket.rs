@@ -0,0 +1,144 @@
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+
+use nix::sys::stat::Mode;
+use nix::unistd::{Gid, Uid};
+use serde::Deserialize;
+use tokio::net::UnixListener;
+
+/// Environment variable overriding the socket path on both sides
+pub const SOCKET_ENV: &str = "USERNS_SOCKET";
+
+/// First file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Default socket path, `$XDG_RUNTIME_DIR/userns.sock`
+///
+/// Falls back to `/tmp/userns.sock` when `XDG_RUNTIME_DIR` is not set.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Path::new(&dir).join("userns.sock"),
+        _ => PathBuf::from("/tmp/userns.sock"),
+    }
+}
+
+/// Parse octal socket mode (e.g. `600` or `0o660`)
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid octal mode: {}", value)),
+    }
+}
+
+/// Owner of the socket file, given as `USER[:GROUP]` (names or IDs)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct SocketOwner {
+    pub uid: Option<Uid>,
+    pub gid: Option<Gid>,
+}
+
+impl TryFrom<String> for SocketOwner {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_owner(&value)
+    }
+}
+
+fn parse_uid(value: &str) -> Result<Uid, String> {
+    if let Ok(uid) = value.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+    match nix::unistd::User::from_name(value) {
+        Ok(Some(user)) => Ok(user.uid),
+        _ => Err(format!("unknown user: {}", value)),
+    }
+}
+
+fn parse_gid(value: &str) -> Result<Gid, String> {
+    if let Ok(gid) = value.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match nix::unistd::Group::from_name(value) {
+        Ok(Some(group)) => Ok(group.gid),
+        _ => Err(format!("unknown group: {}", value)),
+    }
+}
+
+/// Parse `USER[:GROUP]` or `:GROUP`
+pub fn parse_owner(value: &str) -> Result<SocketOwner, String> {
+    let (user, group) = match value.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (value, None),
+    };
+    Ok(SocketOwner {
+        uid: if user.is_empty() { None } else { Some(parse_uid(user)?) },
+        gid: match group {
+            Some(group) if !group.is_empty() => Some(parse_gid(group)?),
+            _ => None,
+        },
+    })
+}
+
+/// Bind a listening socket at path with the given mode and owner
+///
+/// The umask is tightened while binding, so the socket is never
+/// accessible with broader permissions than requested.
+pub fn bind(path: &Path, mode: u32, owner: Option<SocketOwner>) -> std::io::Result<UnixListener> {
+    let old_umask = nix::sys::stat::umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    nix::sys::stat::umask(old_umask);
+    let listener = listener?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    if let Some(owner) = owner {
+        nix::unistd::chown(path, owner.uid, owner.gid)?;
+    }
+    Ok(listener)
+}
+
+/// Take over a listening socket passed with systemd socket activation
+///
+/// Returns None when `LISTEN_PID` / `LISTEN_FDS` are not meant for us.
+/// The variables are removed, so they don't leak into spawned children;
+/// this has to happen before any threads are started.
+pub fn take_listen_fds() -> std::io::Result<Option<std::os::unix::net::UnixListener>> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<i32>().ok());
+    let fds = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid != Some(nix::unistd::getpid().as_raw()) {
+        return Ok(None);
+    }
+    match fds {
+        Some(1) => {},
+        Some(n) if n > 1 => warn!("got {} sockets from LISTEN_FDS, using the first one", n),
+        _ => return Ok(None),
+    }
+    let stat = nix::sys::stat::fstat(SD_LISTEN_FDS_START)?;
+    if nix::sys::stat::SFlag::from_bits_truncate(stat.st_mode) & nix::sys::stat::SFlag::S_IFMT != nix::sys::stat::SFlag::S_IFSOCK {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "LISTEN_FDS descriptor is not a socket"));
+    }
+    nix::fcntl::fcntl(SD_LISTEN_FDS_START, nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC))?;
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mode_is_octal() {
+        assert_eq!(parse_mode("600"), Ok(0o600));
+        assert_eq!(parse_mode("0o660"), Ok(0o660));
+        assert_eq!(parse_mode("0777"), Ok(0o777));
+        for value in ["", "1000", "8", "rw", "-600"] {
+            assert!(parse_mode(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, OFlag};
+use nix::pty::Winsize;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{broadcast, watch};
+use tonic::Status;
+
+/// Output kept for attaching later, per stream
+const LOG_LIMIT: usize = 64 * 1024;
+
+/// Output frames queued for each attached client before it lags
+const OUTPUT_CAPACITY: usize = 256;
+
+const READ_SIZE: usize = 16 * 1024;
+
+/// EOF character written on CloseStdin when there's a PTY
+const VEOF: u8 = 0x04;
+
+/// Chunk of sandbox output
+#[derive(Debug, Clone)]
+pub enum Output {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+}
+
+impl Output {
+    fn len(&self) -> usize {
+        match self {
+            Output::Stdout(data) | Output::Stderr(data) => data.len(),
+        }
+    }
+
+    fn is_stdout(&self) -> bool {
+        matches!(self, Output::Stdout(_))
+    }
+}
+
+/// Descriptors which become stdin / stdout / stderr of the sandbox
+///
+/// They're close-on-exec; dup2(2) in the child clears the flag on 0, 1, 2.
+pub struct ChildStdio {
+    pub stdin: OwnedFd,
+    pub stdout: OwnedFd,
+    pub stderr: OwnedFd,
+    /// stdin is a PTY slave, to become the controlling terminal
+    pub tty: bool,
+}
+
+/// Server ends of the sandbox's stdio, before they're handed to tokio
+pub struct ServerStdio {
+    /// PTY master, or write end of the stdin pipe
+    stdin: OwnedFd,
+    /// None with a PTY, where output is read from the master
+    output: Option<(OwnedFd, OwnedFd)>,
+}
+
+fn internal(what: &str, err: Errno) -> Status {
+    Status::new(tonic::Code::Internal, format!("{}: {}", what, err))
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), Status> {
+    let (r, w) = nix::unistd::pipe2(OFlag::O_CLOEXEC).map_err(|err| internal("pipe", err))?;
+    Ok(unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) })
+}
+
+/// Allocate a PTY whose slave belongs to `owner`
+fn openpty(size: Option<Winsize>, owner: nix::unistd::Uid) -> Result<(OwnedFd, OwnedFd), Status> {
+    let master = nix::pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)
+        .map_err(|err| internal("posix_openpt", err))?;
+    nix::pty::grantpt(&master).map_err(|err| internal("grantpt", err))?;
+    nix::pty::unlockpt(&master).map_err(|err| internal("unlockpt", err))?;
+    let name = nix::pty::ptsname_r(&master).map_err(|err| internal("ptsname", err))?;
+    let master = unsafe { OwnedFd::from_raw_fd(master.into_raw_fd()) };
+    let slave = nix::fcntl::open(name.as_str(), OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty())
+        .map_err(|err| internal("open pty slave", err))?;
+    let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+    // the sandbox doesn't run as us, it must be able to reopen its terminal
+    nix::unistd::fchown(slave.as_raw_fd(), Some(owner), None).map_err(|err| internal("fchown pty slave", err))?;
+    if let Some(size) = size {
+        set_window_size(master.as_raw_fd(), size)?;
+    }
+    Ok((master, slave))
+}
+
+/// PTY size from rows and columns
+pub fn window_size(rows: u16, cols: u16) -> Winsize {
+    Winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 }
+}
+
+fn set_window_size(fd: i32, size: Winsize) -> Result<(), Status> {
+    Errno::result(unsafe { nix::libc::ioctl(fd, nix::libc::TIOCSWINSZ, &size) })
+        .map(drop)
+        .map_err(|err| internal("TIOCSWINSZ", err))
+}
+
+/// Allocate stdio of a sandbox, a PTY if `tty` is set, pipes otherwise
+pub fn open(tty: bool, size: Option<Winsize>, owner: nix::unistd::Uid) -> Result<(ChildStdio, ServerStdio), Status> {
+    if tty {
+        let (master, slave) = openpty(size, owner)?;
+        let child = ChildStdio {
+            stdin: slave.try_clone().map_err(|err| Status::new(tonic::Code::Internal, format!("dup: {}", err)))?,
+            stdout: slave.try_clone().map_err(|err| Status::new(tonic::Code::Internal, format!("dup: {}", err)))?,
+            stderr: slave,
+            tty: true,
+        };
+        return Ok((child, ServerStdio { stdin: master, output: None }));
+    }
+    let (stdin_r, stdin_w) = pipe()?;
+    let (stdout_r, stdout_w) = pipe()?;
+    let (stderr_r, stderr_w) = pipe()?;
+    let child = ChildStdio { stdin: stdin_r, stdout: stdout_w, stderr: stderr_w, tty: false };
+    Ok((child, ServerStdio { stdin: stdin_w, output: Some((stdout_r, stderr_r)) }))
+}
+
+fn async_fd(fd: OwnedFd) -> std::io::Result<AsyncFd<OwnedFd>> {
+    let flags = OFlag::from_bits_truncate(nix::fcntl::fcntl(fd.as_raw_fd(), FcntlArg::F_GETFL)?);
+    nix::fcntl::fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    // the OwnedFd is moved in, so nothing else can close or replace it
+    Ok(unsafe { AsyncFd::register(fd)? })
+}
+
+async fn read_chunk(fd: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let mut guard = fd.readable().await?;
+        match guard.try_io(|inner| nix::unistd::read(inner.as_raw_fd(), buf).map_err(std::io::Error::from)) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+async fn write_all(fd: &AsyncFd<OwnedFd>, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        let mut guard = fd.writable().await?;
+        match guard.try_io(|inner| nix::unistd::write(inner.as_raw_fd(), data).map_err(std::io::Error::from)) {
+            Ok(Ok(n)) => data = &data[n..],
+            Ok(Err(err)) => return Err(err),
+            Err(_would_block) => continue,
+        }
+    }
+    Ok(())
+}
+
+/// Recent output of both streams, bounded by LOG_LIMIT each
+#[derive(Default)]
+struct Logs {
+    frames: VecDeque<Output>,
+    stdout_len: usize,
+    stderr_len: usize,
+}
+
+impl Logs {
+    fn push(&mut self, output: Output) {
+        if output.is_stdout() {
+            self.stdout_len += output.len();
+        } else {
+            self.stderr_len += output.len();
+        }
+        self.frames.push_back(output);
+        while self.stdout_len > LOG_LIMIT || self.stderr_len > LOG_LIMIT {
+            let over_stdout = self.stdout_len > LOG_LIMIT;
+            let index = self.frames.iter().position(|f| f.is_stdout() == over_stdout).unwrap();
+            let frame = self.frames.remove(index).unwrap();
+            if over_stdout {
+                self.stdout_len -= frame.len();
+            } else {
+                self.stderr_len -= frame.len();
+            }
+        }
+    }
+}
+
+/// Server side of a sandbox's stdio, shared by all attached clients
+pub struct SandboxIo {
+    pub tty: bool,
+    /// PTY master or stdin pipe; None once stdin has been closed
+    stdin: tokio::sync::Mutex<Option<Arc<AsyncFd<OwnedFd>>>>,
+    output: broadcast::Sender<Output>,
+    logs: Mutex<Logs>,
+    /// Becomes true once all output has been read
+    drained: watch::Receiver<bool>,
+}
+
+impl SandboxIo {
+    /// Start reading the sandbox's output in background
+    ///
+    /// Output is kept in the logs and forwarded to attached clients.
+    pub fn start(server: ServerStdio) -> std::io::Result<Arc<Self>> {
+        let stdin = Arc::new(async_fd(server.stdin)?);
+        let tty = server.output.is_none();
+        let readers = match server.output {
+            None => vec![(stdin.clone(), true)],
+            Some((stdout, stderr)) => vec![(Arc::new(async_fd(stdout)?), true), (Arc::new(async_fd(stderr)?), false)],
+        };
+        let (output, _) = broadcast::channel(OUTPUT_CAPACITY);
+        let (drained_tx, drained) = watch::channel(false);
+        let io = Arc::new(Self {
+            tty,
+            stdin: tokio::sync::Mutex::new(Some(stdin)),
+            output,
+            logs: Mutex::new(Logs::default()),
+            drained,
+        });
+        let mut tasks = tokio::task::JoinSet::new();
+        for (fd, is_stdout) in readers {
+            let io = io.clone();
+            tasks.spawn(async move { io.pump(&fd, is_stdout).await });
+        }
+        tokio::spawn(async move {
+            while tasks.join_next().await.is_some() {}
+            let _ = drained_tx.send(true);
+        });
+        Ok(io)
+    }
+
+    async fn pump(&self, fd: &AsyncFd<OwnedFd>, is_stdout: bool) {
+        let mut buf = vec![0u8; READ_SIZE];
+        loop {
+            let data = match read_chunk(fd, &mut buf).await {
+                Ok(0) => break,
+                Ok(n) => buf[..n].to_vec(),
+                // PTY master reports EIO once every slave is closed
+                Err(err) if err.raw_os_error() == Some(nix::libc::EIO) => break,
+                Err(err) => {
+                    warn!("read sandbox output: {}", err);
+                    break;
+                },
+            };
+            let frame = if is_stdout { Output::Stdout(data) } else { Output::Stderr(data) };
+            let mut logs = self.logs.lock().unwrap();
+            logs.push(frame.clone());
+            // no receivers just means nobody is attached
+            let _ = self.output.send(frame);
+        }
+    }
+
+    /// Subscribe to output, along with the buffered logs if requested
+    ///
+    /// Both are taken under the same lock, so no frame is missed or duplicated.
+    pub fn subscribe(&self, logs: bool) -> (Vec<Output>, broadcast::Receiver<Output>) {
+        let guard = self.logs.lock().unwrap();
+        let frames = if logs { guard.frames.iter().cloned().collect() } else { vec![] };
+        (frames, self.output.subscribe())
+    }
+
+    /// Receiver which becomes true once all output has been read
+    pub fn drained(&self) -> watch::Receiver<bool> {
+        self.drained.clone()
+    }
+
+    pub async fn write_stdin(&self, data: &[u8]) -> Result<(), Status> {
+        let stdin = self.stdin.lock().await.clone();
+        let stdin = stdin.ok_or_else(|| Status::new(tonic::Code::FailedPrecondition, "stdin has been closed"))?;
+        write_all(&stdin, data)
+            .await
+            .map_err(|err| Status::new(tonic::Code::Unavailable, format!("write stdin: {}", err)))
+    }
+
+    /// Close stdin, or send the EOF character with a PTY
+    pub async fn close_stdin(&self) -> Result<(), Status> {
+        if self.tty {
+            return self.write_stdin(&[VEOF]).await;
+        }
+        self.stdin.lock().await.take();
+        Ok(())
+    }
+
+    pub async fn resize(&self, size: Winsize) -> Result<(), Status> {
+        if !self.tty {
+            return Err(Status::new(tonic::Code::FailedPrecondition, "sandbox has no terminal"));
+        }
+        match self.stdin.lock().await.as_ref() {
+            Some(master) => set_window_size(master.as_raw_fd(), size),
+            None => Err(Status::new(tonic::Code::FailedPrecondition, "terminal has been closed")),
+        }
+    }
+}
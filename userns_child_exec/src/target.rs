@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use nix::errno::Errno;
+use nix::fcntl::{AtFlags, OFlag};
+use nix::sys::stat::Mode;
+
+pub fn pidfd_open(pid: u32) -> nix::Result<OwnedFd> {
+    let res = unsafe { nix::libc::syscall(nix::libc::SYS_pidfd_open, pid as nix::libc::pid_t, 0) };
+    Errno::result(res).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Process whose ID maps are written, pinned by a pidfd
+///
+/// `/proc/PID` is opened once the pidfd is taken and checked to belong to
+/// the same process, and files are only accessed relative to it. If the
+/// process exits, they fail with ESRCH instead of reaching a process which
+/// reused the PID.
+///
+/// The PID itself comes from the client, which has to keep it from being
+/// reused until the request is done, e.g. by not reaping its child.
+pub struct Target {
+    pid: u32,
+    pidfd: OwnedFd,
+    proc_dir: OwnedFd,
+}
+
+impl Target {
+    pub fn open(pid: u32) -> nix::Result<Self> {
+        let pidfd = pidfd_open(pid)?;
+        let proc_dir = nix::fcntl::open(
+            format!("/proc/{}", pid).as_str(),
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let target = Self { pid, pidfd, proc_dir: unsafe { OwnedFd::from_raw_fd(proc_dir) } };
+        // still alive, so the directory can't belong to a process which reused the PID
+        target.check_alive()?;
+        Ok(target)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// ESRCH once the process has exited
+    pub fn check_alive(&self) -> nix::Result<()> {
+        let res = unsafe {
+            nix::libc::syscall(nix::libc::SYS_pidfd_send_signal, self.pidfd.as_raw_fd(), 0, std::ptr::null::<nix::libc::siginfo_t>(), 0)
+        };
+        Errno::result(res).map(drop)
+    }
+
+    /// Parent PID, from `stat`
+    pub fn parent_pid(&self) -> std::io::Result<i32> {
+        let stat = self.read("stat")?;
+        crate::policy::parse_parent_pid(&stat)
+            .ok_or_else(|| std::io::Error::other(format!("no parent PID in /proc/{}/stat", self.pid)))
+    }
+
+    /// Effective UID, from `status`
+    pub fn euid(&self) -> std::io::Result<u32> {
+        let status = self.read("status")?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|ids| ids.split_whitespace().nth(1))
+            .and_then(|euid| euid.parse().ok())
+            .ok_or_else(|| std::io::Error::other(format!("no effective UID in /proc/{}/status", self.pid)))
+    }
+
+    /// UID owning the user namespace the process is in now
+    pub fn user_ns_owner(&self) -> nix::Result<u32> {
+        let fd = nix::fcntl::openat(self.proc_dir.as_raw_fd(), "ns/user", OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+        let ns = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut uid: nix::libc::uid_t = 0;
+        Errno::result(unsafe { nix::libc::ioctl(ns.as_raw_fd(), nix::libc::NS_GET_OWNER_UID, &mut uid) })?;
+        Ok(uid)
+    }
+
+    /// Inode of the user namespace the process is in now
+    pub fn user_ns_inode(&self) -> nix::Result<u64> {
+        let stat = nix::sys::stat::fstatat(self.proc_dir.as_raw_fd(), "ns/user", AtFlags::empty())?;
+        Ok(stat.st_ino)
+    }
+
+    fn open_file(&self, name: &str, flags: OFlag) -> std::io::Result<File> {
+        let fd = nix::fcntl::openat(self.proc_dir.as_raw_fd(), name, flags | OFlag::O_CLOEXEC, Mode::empty())?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Content of `/proc/PID/{name}`
+    pub fn read(&self, name: &str) -> std::io::Result<String> {
+        let mut content = String::new();
+        self.open_file(name, OFlag::O_RDONLY)?.read_to_string(&mut content)?;
+        Ok(content)
+    }
+
+    /// Write content to `/proc/PID/{name}` with a single write(2)
+    pub fn write(&self, name: &str, content: &str) -> std::io::Result<()> {
+        let written = self.open_file(name, OFlag::O_WRONLY)?.write(content.as_bytes())?;
+        if written != content.len() {
+            return Err(std::io::Error::other(format!("short write to {}: {} of {} bytes", name, written, content.len())));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,485 @@
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use prost::Message;
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Code;
+
+use userns::map_request::Type;
+use userns::userns_mapper_client::UsernsMapperClient;
+use userns::{GetMappingRequest, MapRange, MapRequest};
+
+pub mod userns {
+    tonic::include_proto!("userns");
+}
+
+/// `google.rpc.Status` of the `grpc-status-details-bin` trailer
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<prost_types::Any>,
+}
+
+/// `google.rpc.RetryInfo`
+#[derive(Clone, PartialEq, Message)]
+struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    retry_delay: Option<prost_types::Duration>,
+}
+
+/// `google.rpc.ErrorInfo`
+#[derive(Clone, PartialEq, Message)]
+struct ErrorInfo {
+    #[prost(string, tag = "1")]
+    reason: String,
+    #[prost(string, tag = "2")]
+    domain: String,
+    #[prost(map = "string, string", tag = "3")]
+    metadata: HashMap<String, String>,
+}
+
+/// `google.rpc.BadRequest` with its FieldViolations
+#[derive(Clone, PartialEq, Message)]
+struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    field_violations: Vec<FieldViolation>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldViolation {
+    #[prost(string, tag = "1")]
+    field: String,
+    #[prost(string, tag = "2")]
+    description: String,
+}
+
+/// Detail of the given type packed into status
+fn detail<M: Message + Default>(status: &tonic::Status, type_name: &str) -> Option<M> {
+    let rpc_status = RpcStatus::decode(status.details()).ok()?;
+    let type_url = format!("type.googleapis.com/{}", type_name);
+    let any = rpc_status.details.into_iter().find(|any| any.type_url == type_url)?;
+    M::decode(any.value.as_slice()).ok()
+}
+
+/// PID above the largest possible pid_max, so it never exists
+const MISSING_PID: u32 = 1 << 23;
+
+/// How long the server gets to create its socket
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Mapper server running on a socket in a temporary directory
+struct Server {
+    dir: PathBuf,
+    process: Child,
+}
+
+/// Fresh temporary directory holding config.toml with the given content
+fn config_dir(config: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "userns-mapper-test-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    dir
+}
+
+impl Server {
+    /// Start `serve` with the given configuration file content
+    fn start(config: &str) -> Self {
+        let dir = config_dir(config);
+        let process = Command::new(env!("CARGO_BIN_EXE_userns_child_exec"))
+            .arg("--socket")
+            .arg(dir.join("userns.sock"))
+            .arg("--config")
+            .arg(dir.join("config.toml"))
+            // other users connect in the policy tests
+            .args(["--socket-mode", "666", "serve"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Self { dir, process };
+        let started = Instant::now();
+        while !server.socket().exists() {
+            assert!(started.elapsed() < START_TIMEOUT, "server didn't create {}", server.socket().display());
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.dir.join("userns.sock")
+    }
+
+    async fn connect(&self) -> UsernsMapperClient<Channel> {
+        connect(&self.socket()).await
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn connect(socket: &Path) -> UsernsMapperClient<Channel> {
+    let socket = socket.to_path_buf();
+    let channel = Endpoint::try_from("http://any.url")
+        .unwrap()
+        .connect_with_connector(tower::service_fn(move |_: Uri| UnixStream::connect(socket.clone())))
+        .await
+        .unwrap();
+    UsernsMapperClient::new(channel)
+}
+
+/// Process sleeping in a new, still unmapped user namespace
+struct Target(Child);
+
+impl Target {
+    /// None if the kernel doesn't let us create user namespaces
+    fn spawn() -> Option<Self> {
+        Self::spawn_as(own_ids())
+    }
+
+    /// Target owned by another user, if we're root
+    fn spawn_as((uid, gid): (u32, u32)) -> Option<Self> {
+        let mut command = Command::new("sleep");
+        command.arg("60");
+        if nix::unistd::geteuid().is_root() {
+            command.uid(uid).gid(gid);
+        }
+        unsafe {
+            command.pre_exec(|| nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWUSER).map_err(std::io::Error::from));
+        }
+        match command.spawn() {
+            Ok(child) => Some(Self(child)),
+            Err(err) => {
+                eprintln!("skipped, user namespaces are not available: {}", err);
+                None
+            }
+        }
+    }
+
+    fn pid(&self) -> u32 {
+        self.0.id()
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Spawn a Target, or end the test early when user namespaces are unavailable
+macro_rules! target_or_skip {
+    () => {
+        match Target::spawn() {
+            Some(target) => target,
+            None => return,
+        }
+    };
+    ($owner:expr) => {
+        match Target::spawn_as($owner) {
+            Some(target) => target,
+            None => return,
+        }
+    };
+}
+
+fn map_request(kind: Type, pid: u32, inside: u32, outside: u32, length: u32) -> MapRequest {
+    let mut request = MapRequest { pid, ..Default::default() };
+    request.set_type(kind);
+    request.ranges = vec![MapRange { id_inside_ns: inside, id_outside_ns: outside, length }];
+    request
+}
+
+fn own_ids() -> (u32, u32) {
+    (nix::unistd::geteuid().as_raw(), nix::unistd::getegid().as_raw())
+}
+
+/// Run f on a thread with the given credentials, so that the server sees
+/// another caller
+///
+/// Only root can switch; other users run f with their own credentials.
+fn as_user<T: Send + 'static>(uid: u32, gid: u32, f: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::spawn(move || {
+        if nix::unistd::geteuid().is_root() {
+            // the raw syscalls only change this thread, unlike the libc wrappers
+            unsafe {
+                assert_eq!(nix::libc::syscall(nix::libc::SYS_setgroups, 0, std::ptr::null::<nix::libc::gid_t>()), 0);
+                assert_eq!(nix::libc::syscall(nix::libc::SYS_setresgid, gid, gid, gid), 0);
+                assert_eq!(nix::libc::syscall(nix::libc::SYS_setresuid, uid, uid, uid), 0);
+            }
+        }
+        f()
+    })
+    .join()
+    .unwrap()
+}
+
+/// Send a Map request to the server at socket as another user, see as_user()
+fn map_as(uid: u32, gid: u32, socket: PathBuf, request: MapRequest) -> Result<(), tonic::Status> {
+    as_user(uid, gid, move || {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let mut client = connect(&socket).await;
+            client.map(request).await.map(drop)
+        })
+    })
+}
+
+/// Credentials of a caller which isn't root
+fn unprivileged_ids() -> (u32, u32) {
+    if nix::unistd::geteuid().is_root() { (65534, 65534) } else { own_ids() }
+}
+
+#[tokio::test]
+async fn ping() {
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    client.ping(()).await.unwrap();
+}
+
+#[tokio::test]
+async fn map_uid_and_gid() {
+    let target = target_or_skip!();
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, gid) = own_ids();
+    client.map(map_request(Type::Gid, target.pid(), 0, gid, 1)).await.unwrap();
+    client.map(map_request(Type::Uid, target.pid(), 0, uid, 1)).await.unwrap();
+    let mapping = client.get_mapping(GetMappingRequest { pid: target.pid() }).await.unwrap().into_inner();
+    assert_eq!(mapping.uid_map, vec![MapRange { id_inside_ns: 0, id_outside_ns: uid, length: 1 }]);
+    assert_eq!(mapping.gid_map, vec![MapRange { id_inside_ns: 0, id_outside_ns: gid, length: 1 }]);
+    assert_eq!(mapping.setgroups, "deny");
+}
+
+#[tokio::test]
+async fn missing_process_is_not_found() {
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, _) = own_ids();
+    let status = client.map(map_request(Type::Uid, MISSING_PID, 0, uid, 1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let status = client.get_mapping(GetMappingRequest { pid: MISSING_PID }).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn pid_zero_is_invalid() {
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, _) = own_ids();
+    let status = client.map(map_request(Type::Uid, 0, 0, uid, 1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn invalid_ranges_name_the_field() {
+    let target = target_or_skip!();
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, _) = own_ids();
+    let cases = [
+        (map_request(Type::Uid, target.pid(), 0, uid, 0), "Ranges[0].Length", format!("0 {} 0", uid)),
+        (map_request(Type::Uid, target.pid(), u32::MAX, uid, 1), "Ranges[0].IDInsideNS", format!("{} {} 1", u32::MAX, uid)),
+        (map_request(Type::Uid, target.pid(), 0, u32::MAX - 1, 2), "Ranges[0].IDOutsideNS", format!("0 {} 2", u32::MAX - 1)),
+        (MapRequest { pid: target.pid(), length: 0, ..Default::default() }, "Length", "0 0 0".to_string()),
+    ];
+    for (request, field, map) in cases {
+        let status = client.map(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument, "{}", field);
+        let bad_request: BadRequest = detail(&status, "google.rpc.BadRequest").unwrap();
+        assert_eq!(bad_request.field_violations[0].field, field);
+        let info: ErrorInfo = detail(&status, "google.rpc.ErrorInfo").unwrap();
+        assert_eq!(info.reason, "INVALID_REQUEST");
+        assert_eq!(info.metadata["map"], map);
+    }
+    let mut request = map_request(Type::Uid, target.pid(), 0, uid, 1);
+    request.r#type = 7;
+    let status = client.map(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    // nothing reached the kernel
+    let mapping = client.get_mapping(GetMappingRequest { pid: target.pid() }).await.unwrap().into_inner();
+    assert!(mapping.uid_map.is_empty() && mapping.gid_map.is_empty());
+}
+
+#[tokio::test]
+async fn map_is_written_once() {
+    let target = target_or_skip!();
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, _) = own_ids();
+    client.map(map_request(Type::Uid, target.pid(), 0, uid, 1)).await.unwrap();
+    // the same map again is fine, another one isn't
+    client.map(map_request(Type::Uid, target.pid(), 0, uid, 1)).await.unwrap();
+    let status = client.map(map_request(Type::Uid, target.pid(), 1, uid, 1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists);
+}
+
+#[tokio::test]
+async fn process_outside_tree_is_denied() {
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, _) = own_ids();
+    let status = client.map(map_request(Type::Uid, 1, 0, uid, 1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn mapping_query_is_limited_to_own_processes() {
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let status = client.get_mapping(GetMappingRequest { pid: 1 }).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = client.get_mapping(GetMappingRequest { pid: MISSING_PID }).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    assert_eq!(status.message(), format!("process {} does not exist", MISSING_PID));
+}
+
+#[tokio::test]
+async fn process_of_other_user_is_denied() {
+    if !nix::unistd::geteuid().is_root() {
+        // needs a descendant owned by someone else
+        return;
+    }
+    // ours, and so root's, though it's in the caller's process tree
+    let target = target_or_skip!();
+    let server = Server::start("");
+    let (uid, gid) = unprivileged_ids();
+    let status = map_as(uid, gid, server.socket(), map_request(Type::Uid, target.pid(), 0, uid, 1)).unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(status.message(), format!("process {} does not belong to UID {}", target.pid(), uid));
+}
+
+#[tokio::test]
+async fn range_outside_policy_is_denied() {
+    let (uid, gid) = unprivileged_ids();
+    // owned by the caller, so only the range can be the problem
+    let target = target_or_skip!((uid, gid));
+    let server = Server::start("[defaults]\nsubids = false\n");
+    let outside = if uid == 100_000 { 100_001 } else { 100_000 };
+    let pid = target.pid();
+    let status = map_as(uid, gid, server.socket(), map_request(Type::Uid, pid, 0, outside, 1)).unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(status.message(), format!("Uid range {}+1 is not allowed for UID {}", outside, uid));
+    let mut client = server.connect().await;
+    let mapping = client.get_mapping(GetMappingRequest { pid }).await.unwrap().into_inner();
+    assert!(mapping.uid_map.is_empty());
+    // the same caller may map its own UID into the same target
+    map_as(uid, gid, server.socket(), map_request(Type::Uid, pid, 0, uid, 1)).unwrap();
+}
+
+#[tokio::test]
+async fn uid_map_with_setgroups_is_invalid() {
+    let target = target_or_skip!();
+    let server = Server::start("");
+    let mut client = server.connect().await;
+    let (uid, _) = own_ids();
+    let mut request = map_request(Type::Uid, target.pid(), 0, uid, 1);
+    request.allow_setgroups = true;
+    let status = client.map(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn setgroups_allow_needs_subordinate_gids() {
+    let (uid, gid) = unprivileged_ids();
+    let target = target_or_skip!((uid, gid));
+    let server = Server::start(&format!("[users.{}]\ngid_ranges = [\"200000:10\"]\n", uid));
+    // assigned ranges don't matter for a map of just the own GID
+    let mut request = map_request(Type::Gid, target.pid(), 0, gid, 1);
+    request.allow_setgroups = true;
+    let status = map_as(uid, gid, server.socket(), request).unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert!(status.message().contains("setgroups"), "{}", status.message());
+    if !nix::unistd::geteuid().is_root() {
+        // only root may write subordinate GIDs with the direct backend
+        return;
+    }
+    let mut request = map_request(Type::Gid, target.pid(), 0, gid, 1);
+    request.ranges.push(MapRange { id_inside_ns: 1, id_outside_ns: 200_000, length: 10 });
+    request.allow_setgroups = true;
+    map_as(uid, gid, server.socket(), request).unwrap();
+    let mut client = server.connect().await;
+    let mapping = client.get_mapping(GetMappingRequest { pid: target.pid() }).await.unwrap().into_inner();
+    assert_eq!(mapping.setgroups, "allow");
+}
+
+#[tokio::test]
+async fn burst_over_rate_limit_is_exhausted() {
+    let server = Server::start("rate_limit = 1.0\nrate_burst = 2\n");
+    let mut client = server.connect().await;
+    client.ping(()).await.unwrap();
+    client.ping(()).await.unwrap();
+    let status = client.ping(()).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert_eq!(status.metadata().get("retry-after").unwrap(), "1");
+    let retry_info: RetryInfo = detail(&status, "google.rpc.RetryInfo").unwrap();
+    let delay = retry_info.retry_delay.unwrap();
+    let delay = Duration::new(delay.seconds as u64, delay.nanos as u32);
+    assert!(delay > Duration::ZERO && delay <= Duration::from_secs(1), "{:?}", delay);
+}
+
+#[tokio::test]
+async fn socket_owner_is_read_from_config() {
+    use std::os::unix::fs::MetadataExt;
+
+    if !nix::unistd::geteuid().is_root() {
+        // only root may give the socket away
+        return;
+    }
+    let server = Server::start("socket_owner = \"65534:65534\"\n");
+    // served only once bind is done with the socket
+    server.connect().await.ping(()).await.unwrap();
+    let metadata = std::fs::metadata(server.socket()).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (65534, 65534));
+}
+
+#[test]
+fn invalid_rate_limit_is_rejected() {
+    for rate in ["0", "-1", "NaN", "inf"] {
+        let dir = config_dir(&format!("rate_limit = {}\n", if rate == "NaN" { "nan" } else { rate }));
+        let from_cli = Command::new(env!("CARGO_BIN_EXE_userns_child_exec"))
+            .arg("--socket")
+            .arg(dir.join("userns.sock"))
+            .arg(format!("--rate-limit={}", rate))
+            .arg("serve")
+            .output()
+            .unwrap();
+        let from_config = Command::new(env!("CARGO_BIN_EXE_userns_child_exec"))
+            .arg("--socket")
+            .arg(dir.join("userns.sock"))
+            .arg("--config")
+            .arg(dir.join("config.toml"))
+            .arg("serve")
+            .output()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        for output in [from_cli, from_config] {
+            assert!(!output.status.success(), "rate {} was accepted", rate);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains("rate must be a positive number"), "{}", stderr);
+        }
+    }
+}